@@ -0,0 +1,52 @@
+// Skip list nodes are variable sized (their size depends on their height), so
+// they're allocated directly rather than through Box. This file contains the
+// hook for replacing the allocator used for nodes, so lists can live in an
+// arena or in an allocator which tracks memory usage.
+
+use std::alloc::{alloc, dealloc, Layout};
+use std::ptr::NonNull;
+
+/// An allocator for skip list nodes. This mirrors the shape of the (unstable)
+/// std::alloc::Allocator trait, but only has the methods the list needs.
+///
+/// # Safety
+/// Memory returned by allocate must be valid for reads and writes of
+/// layout.size() bytes, aligned to layout.align(), and must stay valid until it
+/// is passed to deallocate. Moving the allocator must not invalidate memory it
+/// has handed out, since the list is allowed to move.
+pub unsafe trait NodeAllocator {
+    /// Allocate a block of memory for the specified layout. Returning None
+    /// signals allocation failure, which will abort via handle_alloc_error.
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// Release a block of memory previously returned by allocate.
+    ///
+    /// # Safety
+    /// ptr must have been returned by a call to allocate on this allocator,
+    /// with the same layout.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+/// The default node allocator, which uses the global allocator.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Global;
+
+unsafe impl NodeAllocator for Global {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { alloc(layout) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+unsafe impl<A: NodeAllocator> NodeAllocator for &A {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        (**self).allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        (**self).deallocate(ptr, layout)
+    }
+}
@@ -0,0 +1,249 @@
+// Anchors are positions between items which follow the items around them as
+// the list is edited, for things like cursors and selection ends. Use Anchors
+// as (part of) the list's notify target.
+//
+// The anchors are kept as points in a skip list of their own, where each
+// point's size is its distance from the point before it. Every point has a
+// marker, so an anchor is resolved by walking up the parent pointers from its
+// node, in O(log n). An edit to the list only touches the points around where
+// the edit was made. Deleting the items either side of an anchor just moves
+// it to where they were, so there's nothing to clean up afterwards.
+
+use std::collections::HashMap;
+
+use {Edit, ItemMarker, ListItem, NotifyTarget, SkipList};
+use skiplist::{ReplaceFilter, SplitFilter};
+
+/// Which way an anchor leans when items are inserted exactly at it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bias {
+    /// Stay before inserted items.
+    Left,
+    /// Stay after inserted items.
+    Right,
+}
+
+/// A position between two items, made by Anchors::add.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Anchor(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Point {
+    /// The distance from the point before this one (or from the start of the
+    /// list).
+    before: usize,
+    anchor: Anchor,
+    bias: Bias,
+}
+
+impl ListItem for Point {
+    fn get_usersize(&self) -> usize { self.before }
+}
+
+#[derive(Debug, Default)]
+struct PointMarkers(HashMap<Anchor, ItemMarker<Point>>);
+
+impl NotifyTarget<Point> for PointMarkers {
+    fn on_set(&mut self, items: &[Point], at_marker: ItemMarker<Point>) {
+        for point in items { self.0.insert(point.anchor, at_marker); }
+    }
+
+    fn on_delete(&mut self, items: &[Point]) {
+        for point in items { self.0.remove(&point.anchor); }
+    }
+}
+
+/// Tracks anchors in a list. Use this as (part of) the list's notify target.
+/// It must see every edit made to the list.
+///
+/// Points at the same position are kept with the left biased ones first, so
+/// an insert there goes between the two groups.
+#[derive(Debug)]
+pub struct Anchors {
+    // Boxed because the point markers can point into the list's inline head,
+    // which must not move.
+    points: Box<SkipList<Point, PointMarkers>>,
+    markers: PointMarkers,
+    next_id: u64,
+    splits: SplitFilter,
+    replaces: ReplaceFilter,
+}
+
+impl Anchors {
+    pub fn new() -> Self {
+        Anchors {
+            points: Box::new(SkipList::new()),
+            markers: PointMarkers::default(),
+            next_id: 0,
+            splits: SplitFilter::default(),
+            replaces: ReplaceFilter::default(),
+        }
+    }
+
+    /// The number of anchors.
+    pub fn len(&self) -> usize { self.markers.0.len() }
+    pub fn is_empty(&self) -> bool { self.markers.0.is_empty() }
+
+    /// Make an anchor at userpos, which should be no more than the list's
+    /// len_user().
+    pub fn add(&mut self, userpos: usize, bias: Bias) -> Anchor {
+        let anchor = Anchor(self.next_id);
+        self.next_id += 1;
+
+        let end = self.points.len_user();
+        let (mut edit, mut pos) = self.edit_before(userpos.min(end));
+        // Go past any points which stay before the new one.
+        while let Some(point) = edit.current_item() {
+            let at = pos + point.before;
+            if at > userpos || (at == userpos && bias == Bias::Left && point.bias == Bias::Right) { break; }
+            pos = at;
+            edit.advance_item();
+        }
+
+        let before = userpos - pos;
+        edit.insert(Point { before, anchor, bias });
+        if edit.current_item().is_some() {
+            edit.modify_current_item(|next| next.before -= before);
+        }
+        anchor
+    }
+
+    /// Stop tracking an anchor. Returns false if it wasn't being tracked.
+    pub fn remove(&mut self, anchor: Anchor) -> bool {
+        let marker = match self.markers.0.get(&anchor) {
+            Some(marker) => *marker,
+            None => return false,
+        };
+        // The markers are kept up to date by the points list, which never
+        // moves.
+        let mut edit = unsafe {
+            self.points.edit_at_marker_exact(&mut self.markers, marker, |point| point.anchor == anchor)
+        }.unwrap();
+        let before = edit.current_item().unwrap().before;
+        edit.del(1);
+        if edit.current_item().is_some() {
+            edit.modify_current_item(|next| next.before += before);
+        }
+        true
+    }
+
+    /// Find the current position of an anchor. This is O(log n). Returns
+    /// None if the anchor has been removed.
+    pub fn resolve(&self, anchor: Anchor) -> Option<usize> {
+        let marker = *self.markers.0.get(&anchor)?;
+        let mut before = 0;
+        // As in remove, the markers are always up to date.
+        let start = unsafe {
+            self.points.userpos_at_marker(marker, |point| {
+                if point.anchor == anchor { before = point.before; true } else { false }
+            })
+        }?;
+        Some(start + before)
+    }
+
+    // Get an edit just before the first point at or after userpos, and the
+    // position of the point before that (which the edit is at).
+    fn edit_before(&mut self, userpos: usize) -> (Edit<'_, Point, PointMarkers>, usize) {
+        let (mut edit, offset) = self.points.edit_n(&mut self.markers, userpos);
+        if offset > 0 {
+            // Between two points. Seeking to the first lands before any other
+            // points at the same position, so step over them.
+            edit.seek_to(userpos - offset);
+            while edit.current_item().unwrap().before == 0 { edit.advance_item(); }
+        } else if userpos > 0 {
+            // Just after the first point at userpos.
+            edit.retreat_item();
+        }
+        let pos = edit.user_position();
+        (edit, pos)
+    }
+
+    // Items were inserted into the list. Points after userpos move along,
+    // as do right biased points at userpos (and left biased ones too if
+    // move_left is set).
+    fn grow(&mut self, userpos: usize, len: usize, move_left: bool) {
+        if len == 0 || self.is_empty() || userpos > self.points.len_user() { return; }
+
+        let (mut edit, mut pos) = self.edit_before(userpos);
+        while let Some(point) = edit.current_item() {
+            let at = pos + point.before;
+            if at > userpos || move_left || point.bias == Bias::Right { break; }
+            pos = at;
+            edit.advance_item();
+        }
+        // Moving the first point moves everything after it.
+        if edit.current_item().is_some() {
+            edit.modify_current_item(|point| point.before += len);
+        }
+    }
+
+    // Items were removed from the list. Points inside the removed range move
+    // to userpos, and points after it move back.
+    fn shrink(&mut self, userpos: usize, len: usize) {
+        if len == 0 || self.is_empty() || userpos >= self.points.len_user() { return; }
+        let end = userpos + len;
+
+        // Take out the points from userpos to end, and put them back sorted
+        // by bias.
+        let (mut edit, start) = self.edit_before(userpos);
+        let mut pos = start;
+        let mut collapsed = Vec::new();
+        while let Some(point) = edit.current_item().copied() {
+            if pos + point.before > end { break; }
+            pos += point.before;
+            collapsed.push(point);
+            edit.del(1);
+        }
+        collapsed.sort_by_key(|point| point.bias == Bias::Right);
+
+        let mut last = start;
+        if let Some(first) = collapsed.first_mut() {
+            first.before = userpos - start;
+            for point in &mut collapsed[1..] { point.before = 0; }
+            edit.insert_iter(collapsed.into_iter());
+            last = userpos;
+        }
+        if edit.current_item().is_some() {
+            edit.modify_current_item(|next| next.before = pos + next.before - len - last);
+        }
+    }
+}
+
+impl Default for Anchors {
+    fn default() -> Self { Self::new() }
+}
+
+impl<Item: ListItem> NotifyTarget<Item> for Anchors {
+    fn on_set(&mut self, _items: &[Item], _at_marker: ItemMarker<Item>) {}
+    fn on_delete(&mut self, _items: &[Item]) {
+        self.replaces.on_delete();
+    }
+
+    fn on_insert(&mut self, userpos: usize, items: &[Item], _at_marker: ItemMarker<Item>) {
+        let len = Item::userlen_of_slice(items);
+        if self.splits.take_insert(len) { return; }
+
+        match self.replaces.take_replaced(userpos, items) {
+            // An anchor at the end of a replaced item stays at its end.
+            Some(changes) => for (pos, old_len, new_len) in changes {
+                if new_len > old_len {
+                    self.grow(pos + old_len, new_len - old_len, old_len > 0);
+                } else {
+                    self.shrink(pos + new_len, old_len - new_len);
+                }
+            },
+            None => self.grow(userpos, len, false),
+        }
+    }
+
+    fn on_remove(&mut self, userpos: usize, items: &[Item]) {
+        if self.splits.take_remove() { return; }
+        if self.replaces.take_delete(userpos, items) {
+            self.shrink(userpos, Item::userlen_of_slice(items));
+        }
+    }
+
+    fn on_split(&mut self, _userpos: usize, item: &Item, _offset: usize) {
+        self.splits.on_split(item.get_usersize());
+    }
+}
@@ -0,0 +1,363 @@
+// Annotations attach values to ranges of a list, like highlights or comments
+// on a document. Use Annotations as (part of) the list's notify target, and
+// the ranges follow the list's inserts and deletes.
+//
+// Internally the list's length is divided into spans, where every position in
+// a span is covered by the same annotations. The spans are stored in a skip
+// list of their own (keyed by length), so finding the spans around a position
+// is O(log n). Each span also records which annotations start and end at its
+// edges, and those spans are tracked with markers so an annotation's range can
+// be found in O(log n) as well.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use {ItemMarker, ListItem, NotifyTarget, SkipList, SimpleApi};
+use skiplist::{ReplaceFilter, SplitFilter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AnnotationId(u64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Span {
+    len: usize,
+    /// The annotations covering this span, sorted.
+    ids: Vec<AnnotationId>,
+    /// The annotations which start at the start of this span.
+    starts: Vec<AnnotationId>,
+    /// The annotations which end at the end of this span.
+    ends: Vec<AnnotationId>,
+}
+
+impl Span {
+    fn new(len: usize, ids: Vec<AnnotationId>) -> Self {
+        Span { len, ids, starts: Vec::new(), ends: Vec::new() }
+    }
+}
+
+impl ListItem for Span {
+    fn get_usersize(&self) -> usize { self.len }
+
+    fn split_item(&self, at: usize) -> (Self, Self) {
+        (Span { len: at, ids: self.ids.clone(), starts: self.starts.clone(), ends: Vec::new() },
+            Span { len: self.len - at, ids: self.ids.clone(), starts: Vec::new(), ends: self.ends.clone() })
+    }
+}
+
+// Tracks the spans where each annotation starts and ends.
+#[derive(Debug, Default)]
+struct Endpoints {
+    starts: HashMap<AnnotationId, ItemMarker<Span>>,
+    ends: HashMap<AnnotationId, ItemMarker<Span>>,
+}
+
+impl NotifyTarget<Span> for Endpoints {
+    fn on_set(&mut self, items: &[Span], at_marker: ItemMarker<Span>) {
+        for span in items {
+            for id in &span.starts { self.starts.insert(*id, at_marker); }
+            for id in &span.ends { self.ends.insert(*id, at_marker); }
+        }
+    }
+
+    fn on_delete(&mut self, items: &[Span]) {
+        for span in items {
+            for id in &span.starts { self.starts.remove(id); }
+            for id in &span.ends { self.ends.remove(id); }
+        }
+    }
+}
+
+/// Values attached to ranges of a list.
+///
+/// Text inserted strictly inside an annotation extends it. Text inserted at
+/// either end of an annotation is left outside it. Deleting part of an
+/// annotation's range shrinks it, and deleting all of it removes the
+/// annotation.
+///
+/// Items overwritten by replace_at keep the annotations which covered them.
+/// If a replacement is bigger than the item it replaces, the extra length is
+/// added to the end of the item's range. If it's smaller, the end of the
+/// item's range is removed.
+///
+/// Changes to an item's size made in place (with modify_item_after or
+/// modify_prev_item) aren't reported as inserts or removals, so they aren't
+/// seen here. Use replace_at instead.
+#[derive(Debug)]
+pub struct Annotations<V> {
+    // Boxed because the endpoint markers can point into the list's inline
+    // head, which must not move.
+    spans: Box<SkipList<Span, Endpoints>>,
+    endpoints: Endpoints,
+    values: HashMap<AnnotationId, V>,
+    next_id: u64,
+    // Splitting an item doesn't change the list's contents, so the removal
+    // and re-insertion it's reported as are ignored.
+    splits: SplitFilter,
+    // Overwriting an item with replace_at is a change in place, not a delete
+    // and an insert.
+    replaces: ReplaceFilter,
+}
+
+impl<V> Annotations<V> {
+    /// Create annotations for an empty list.
+    pub fn new() -> Self {
+        Annotations {
+            spans: Box::new(SkipList::new()),
+            endpoints: Endpoints::default(),
+            values: HashMap::new(),
+            next_id: 0,
+            splits: SplitFilter::default(),
+            replaces: ReplaceFilter::default(),
+        }
+    }
+
+    /// Create annotations for a list which already has len_user() == len.
+    pub fn with_len(len: usize) -> Self {
+        let mut a = Self::new();
+        a.grow(0, len);
+        a
+    }
+
+    /// The number of annotations.
+    pub fn len(&self) -> usize { self.values.len() }
+    pub fn is_empty(&self) -> bool { self.values.is_empty() }
+
+    pub fn get(&self, id: AnnotationId) -> Option<&V> { self.values.get(&id) }
+    pub fn get_mut(&mut self, id: AnnotationId) -> Option<&mut V> { self.values.get_mut(&id) }
+
+    /// Attach value to the (non-empty) range.
+    pub fn add(&mut self, range: Range<usize>, value: V) -> AnnotationId {
+        assert!(range.start < range.end && range.end <= self.spans.len_user(), "Invalid annotation range");
+        let id = AnnotationId(self.next_id);
+        self.next_id += 1;
+
+        self.split_at(range.start);
+        self.split_at(range.end);
+        let mut edit = self.spans.edit_between_n(&mut self.endpoints, range.start);
+        let mut pos = range.start;
+        while pos < range.end {
+            let end = pos + edit.current_item().unwrap().len;
+            edit.modify_current_item(|span| {
+                // Ids are handed out in order, so this keeps them sorted.
+                span.ids.push(id);
+                if pos == range.start { span.starts.push(id); }
+                if end == range.end { span.ends.push(id); }
+            });
+            pos = end;
+        }
+
+        self.values.insert(id, value);
+        id
+    }
+
+    /// Remove an annotation, returning its value.
+    pub fn remove(&mut self, id: AnnotationId) -> Option<V> {
+        let range = self.range(id)?;
+        {
+            let mut edit = self.spans.edit_between_n(&mut self.endpoints, range.start);
+            while edit.user_position() < range.end {
+                edit.modify_current_item(|span| {
+                    span.ids.retain(|i| *i != id);
+                    span.starts.retain(|i| *i != id);
+                    span.ends.retain(|i| *i != id);
+                });
+            }
+        }
+        self.endpoints.starts.remove(&id);
+        self.endpoints.ends.remove(&id);
+        self.merge_at(range.start);
+        self.merge_at(range.end);
+        self.values.remove(&id)
+    }
+
+    /// Find the range currently covered by an annotation. This is O(log n).
+    pub fn range(&self, id: AnnotationId) -> Option<Range<usize>> {
+        let start_marker = *self.endpoints.starts.get(&id)?;
+        let end_marker = *self.endpoints.ends.get(&id)?;
+        // The markers are kept up to date by the spans list, which never moves.
+        unsafe {
+            let start = self.spans.userpos_at_marker(start_marker, |span| span.starts.contains(&id))?;
+            let mut len = 0;
+            let end = self.spans.userpos_at_marker(end_marker, |span| {
+                if span.ends.contains(&id) { len = span.len; true } else { false }
+            })?;
+            Some(start..end + len)
+        }
+    }
+
+    /// Find the annotations which overlap range. This is O(log n + k) for k
+    /// results. Each annotation is returned once, in order of where it
+    /// starts (annotations which start before range first).
+    pub fn overlapping(&self, range: Range<usize>) -> Vec<(AnnotationId, &V)> {
+        let mut result = Vec::new();
+        if range.start >= range.end || range.start >= self.spans.len_user() { return result; }
+
+        let (cursor, offset) = self.spans.cursor_at_userpos(range.start);
+        let mut pos = range.start - offset;
+        let spans = unsafe { &*cursor.here_ptr() }.iter(cursor.local_index);
+        for (i, span) in spans.enumerate() {
+            if pos >= range.end { break; }
+            // Every annotation in a span is either in the previous span too,
+            // or starts here.
+            let ids = if i == 0 { &span.ids } else { &span.starts };
+            result.extend(ids.iter().map(|id| (*id, &self.values[id])));
+            pos += span.len;
+        }
+        result
+    }
+
+    // Split the span containing pos, so there's a span boundary at pos.
+    fn split_at(&mut self, pos: usize) {
+        let (mut edit, offset) = self.spans.edit_n(&mut self.endpoints, pos);
+        if offset > 0 {
+            let (a, b) = edit.current_item().unwrap().split_item(offset);
+            edit.seek_to(pos - offset);
+            edit.replace(1, vec![a, b].into_iter());
+        }
+    }
+
+    // Merge the spans either side of pos if they're covered by the same
+    // annotations.
+    fn merge_at(&mut self, pos: usize) {
+        if pos == 0 || pos >= self.spans.len_user() { return; }
+        let mut edit = self.spans.edit_between_n(&mut self.endpoints, pos);
+        let next = match (edit.prev_item(), edit.current_item()) {
+            (Some(prev), Some(next)) if prev.ids == next.ids => next.clone(),
+            _ => return,
+        };
+        // Matching ids means nothing ends at prev or starts at next. Delete
+        // next before moving its ends across, so the endpoint markers are
+        // removed and then set rather than the other way around.
+        edit.del(1);
+        edit.modify_prev_item(|prev| {
+            prev.len += next.len;
+            prev.ends = next.ends;
+        });
+    }
+
+    // Items were inserted into the list.
+    fn grow(&mut self, pos: usize, len: usize) {
+        if len == 0 { return; }
+        if self.spans.len_user() == 0 {
+            self.spans.notify(&mut self.endpoints).insert_at(0, std::iter::once(Span::new(len, Vec::new())));
+            return;
+        }
+
+        let (mut edit, offset) = self.spans.edit_n(&mut self.endpoints, pos);
+        if offset > 0 {
+            // Inside a span. Make it bigger.
+            edit.seek_to(pos - offset);
+            edit.modify_current_item(|span| span.len += len);
+            return;
+        }
+
+        // Between two spans. The new items are covered by the annotations
+        // which continue across the gap, so join whichever neighbour has
+        // exactly those annotations.
+        let prev_ids = edit.prev_item().map(|span| span.ids.clone());
+        let next_ids = edit.current_item().map(|span| span.ids.clone());
+        let ids: Vec<AnnotationId> = match (&prev_ids, &next_ids) {
+            (Some(prev), Some(next)) => prev.iter().filter(|id| next.contains(id)).copied().collect(),
+            _ => Vec::new(),
+        };
+        if prev_ids.as_ref() == Some(&ids) {
+            edit.modify_prev_item(|span| span.len += len);
+        } else if next_ids.as_ref() == Some(&ids) {
+            edit.modify_current_item(|span| span.len += len);
+        } else {
+            edit.insert(Span::new(len, ids));
+        }
+    }
+
+    // An item of size old_len at pos was overwritten by one of size new_len.
+    fn resize(&mut self, pos: usize, old_len: usize, new_len: usize) {
+        if old_len == 0 {
+            self.grow(pos, new_len);
+        } else if new_len > old_len {
+            // Make the span holding the item's last position bigger.
+            let last = pos + old_len - 1;
+            let (mut edit, offset) = self.spans.edit_n(&mut self.endpoints, last);
+            edit.seek_to(last - offset);
+            edit.modify_current_item(|span| span.len += new_len - old_len);
+        } else {
+            self.shrink(pos + new_len, old_len - new_len);
+        }
+    }
+
+    // Items were removed from the list.
+    fn shrink(&mut self, pos: usize, len: usize) {
+        if len == 0 { return; }
+        let end = pos + len;
+        self.split_at(pos);
+        self.split_at(end);
+
+        let mut count = 0;
+        let mut started = Vec::new();
+        let mut ended = Vec::new();
+        {
+            let (cursor, _) = self.spans.cursor_at_userpos(pos);
+            let mut p = pos;
+            for span in unsafe { &*cursor.here_ptr() }.iter(cursor.local_index) {
+                if p >= end { break; }
+                count += 1;
+                started.extend_from_slice(&span.starts);
+                ended.extend_from_slice(&span.ends);
+                p += span.len;
+            }
+        }
+
+        // Annotations which started and ended inside the removed range are
+        // gone. The others now start or end at pos.
+        let moved_starts: Vec<AnnotationId> = started.iter().filter(|id| !ended.contains(id)).copied().collect();
+        let moved_ends: Vec<AnnotationId> = ended.iter().filter(|id| !started.contains(id)).copied().collect();
+        for id in started.iter().filter(|id| ended.contains(id)) {
+            self.values.remove(id);
+        }
+
+        {
+            let mut edit = self.spans.edit_between_n(&mut self.endpoints, pos);
+            edit.del(count);
+            if !moved_ends.is_empty() {
+                edit.modify_prev_item(|span| span.ends.extend_from_slice(&moved_ends));
+            }
+            if !moved_starts.is_empty() {
+                edit.modify_current_item(|span| span.starts.extend_from_slice(&moved_starts));
+            }
+        }
+        self.merge_at(pos);
+    }
+}
+
+impl<V> Default for Annotations<V> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<Item: ListItem, V> NotifyTarget<Item> for Annotations<V> {
+    fn on_set(&mut self, _items: &[Item], _at_marker: ItemMarker<Item>) {}
+    fn on_delete(&mut self, _items: &[Item]) {
+        self.replaces.on_delete();
+    }
+
+    fn on_insert(&mut self, userpos: usize, items: &[Item], _at_marker: ItemMarker<Item>) {
+        let len = Item::userlen_of_slice(items);
+        if self.splits.take_insert(len) { return; }
+
+        match self.replaces.take_replaced(userpos, items) {
+            Some(changes) => for (pos, old_len, new_len) in changes {
+                self.resize(pos, old_len, new_len);
+            },
+            None => self.grow(userpos, len),
+        }
+    }
+
+    fn on_remove(&mut self, userpos: usize, items: &[Item]) {
+        if self.splits.take_remove() { return; }
+        if self.replaces.take_delete(userpos, items) {
+            self.shrink(userpos, Item::userlen_of_slice(items));
+        }
+    }
+
+    fn on_split(&mut self, _userpos: usize, item: &Item, _offset: usize) {
+        self.splits.on_split(item.get_usersize());
+    }
+}
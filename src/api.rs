@@ -1,16 +1,89 @@
 // This file contains the public facing editing API for skip lists.
 
-use std::iter;
-use {ListItem, ListItemIter, NotifyTarget, SkipList, Cursor, ItemMarker};
-
-pub struct Edit<'a, Item: ListItem, N: NotifyTarget<Item> = ()> {
-    list: &'a mut SkipList<Item, N>,
+use std::{iter, ptr};
+use std::ops::Range;
+use std::vec;
+use {ListItem, ListItemIter, NotifyTarget, SkipList, Cursor, ItemMarker, Op, Recorder};
+use allocator::{NodeAllocator, Global};
+
+pub struct Edit<'a, Item: ListItem, N: NotifyTarget<Item> = (), A: NodeAllocator = Global> {
+    list: &'a mut SkipList<Item, N, A>,
     cursor: Cursor<Item>,
     // item_offset: usize, // Offset into the current item.
     notify: &'a mut N,
 }
 
-impl<'a, Item: ListItem, N: NotifyTarget<Item>> Edit<'a, Item, N> {
+/// Where an insert put the new items.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inserted<Item: ListItem> {
+    /// The userpos range covered by the inserted items.
+    pub range: Range<usize>,
+    /// A marker for each inserted item, in order. Like any marker, these go
+    /// stale when their items are moved to other nodes, which is reported
+    /// through the list's notify target.
+    pub markers: Vec<ItemMarker<Item>>,
+}
+
+/// Items being inserted by an Edit. When the list is being recorded they're
+/// collected first, so a copy can be logged.
+enum Contents<I, Item> {
+    Direct(I),
+    Collected(vec::IntoIter<Item>),
+}
+
+impl<I: Iterator<Item=Item>, Item> Iterator for Contents<I, Item> {
+    type Item = Item;
+    fn next(&mut self) -> Option<Item> {
+        match self {
+            Contents::Direct(iter) => iter.next(),
+            Contents::Collected(iter) => iter.next(),
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Contents::Direct(iter) => iter.size_hint(),
+            Contents::Collected(iter) => iter.size_hint(),
+        }
+    }
+}
+
+impl<I: ExactSizeIterator<Item=Item>, Item> ExactSizeIterator for Contents<I, Item> {}
+
+impl<'a, Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> Edit<'a, Item, N, A> {
+    /// Log an edit if the list is being recorded. make_op is only called when
+    /// it is.
+    fn record<F: FnOnce(&Recorder<Item>) -> Op<Item>>(&mut self, make_op: F) {
+        if let Some(recorder) = self.list.recorder.as_mut() {
+            let op = make_op(recorder);
+            recorder.log(op);
+        }
+    }
+
+    /// Log an edit which inserts contents, if the list is being recorded.
+    fn record_insert<I, F>(&mut self, contents: I, make_op: F) -> Contents<I, Item>
+            where I: ExactSizeIterator<Item=Item>, F: FnOnce(Vec<Item>) -> Op<Item> {
+        if self.list.recorder.is_none() { return Contents::Direct(contents); }
+
+        let items: Vec<Item> = contents.collect();
+        self.record(|recorder| make_op(recorder.clone_items(&items)));
+        Contents::Collected(items.into_iter())
+    }
+
+    /// How many zero sized items the cursor is after, past where
+    /// cursor_at_userpos would put it. This is only counted in debug builds,
+    /// for dbg_check_cursor_at.
+    fn dbg_zero_sized_before(&self) -> usize {
+        let mut skipped = 0;
+        if cfg!(debug_assertions) {
+            let (mut c2, _) = self.list.cursor_at_userpos(self.cursor.userpos);
+            while c2 != self.cursor && unsafe { c2.current_item() }.is_some_and(|i| i.get_usersize() == 0) {
+                c2.advance_item(self.list.height());
+                skipped += 1;
+            }
+        }
+        skipped
+    }
+
     fn dbg_check_cursor_at(&self, userpos: usize, plus_items: usize) {
         if cfg!(debug_assertions) {
             let (mut c2, _) = self.list.cursor_at_userpos(userpos);
@@ -20,49 +93,106 @@ impl<'a, Item: ListItem, N: NotifyTarget<Item>> Edit<'a, Item, N> {
     }
 
     pub fn del(&mut self, num_items: usize) {
-        unsafe { self.list.del_at_iter(&self.cursor, num_items, &mut self.notify); }
+        let pos = self.cursor.userpos;
+        self.record(|_| Op::Delete { pos, count: num_items });
+        unsafe { self.list.del_at_iter(&mut self.cursor, num_items, self.notify); }
 
         if cfg!(debug_assertions) {
-            let (c2, _) = self.list.cursor_at_userpos(self.cursor.userpos);
+            let (mut c2, _) = self.list.cursor_at_userpos(self.cursor.userpos);
+            // The cursor may legitimately be after some zero sized items.
+            while c2 != self.cursor && unsafe { c2.current_item() }.is_some_and(|i| i.get_usersize() == 0) {
+                c2.advance_item(self.list.height());
+            }
             if self.cursor != c2 { panic!("Invalid cursor after delete"); }
         }
+        self.list.save_cursor(&self.cursor);
     }
 
-    pub fn insert_iter<I>(&mut self, mut contents: I) where I: ExactSizeIterator<Item=Item> {
+    pub fn insert_iter<I>(&mut self, contents: I) where I: ExactSizeIterator<Item=Item> {
         if contents.len() == 0 { return; }
         let num_inserted_items = contents.len();
         let start_userpos = self.cursor.userpos;
+        let skipped = self.dbg_zero_sized_before();
+        let mut contents = self.record_insert(contents, |items| Op::Insert { pos: start_userpos, items });
 
         unsafe {
-            self.list.insert_at_iter(&mut self.cursor, &mut contents, &mut self.notify);
+            self.list.insert_at_iter(&mut self.cursor, &mut contents, self.notify);
 
-            self.dbg_check_cursor_at(start_userpos, num_inserted_items);
+            self.dbg_check_cursor_at(start_userpos, skipped + num_inserted_items);
         }
+        self.list.save_cursor(&self.cursor);
     }
 
-    pub fn insert_between_iter<I>(&mut self, offset: usize, mut contents: I) where I: ExactSizeIterator<Item=Item> {
+    pub fn insert_between_iter<I>(&mut self, offset: usize, contents: I) where I: ExactSizeIterator<Item=Item> {
         if offset == 0 { return self.insert_iter(contents); }
 
         let num_inserted_items = contents.len();
         let start_userpos = self.cursor.userpos;
+        // This is logged even when contents is empty, since it still splits
+        // the item.
+        let mut contents = self.record_insert(contents, |items| Op::Insert { pos: start_userpos, items });
 
         unsafe {
-            let current_item = self.cursor.current_item();
-            let (start, end) = Item::split_item(current_item.unwrap(), offset);
+            let current_item = self.cursor.current_item().unwrap();
+            self.notify.on_split(start_userpos - offset, current_item, offset);
+            let (start, end) = Item::split_item(current_item, offset);
             // Move the cursor back to the start of the item we're
             // splitting.
             self.cursor.move_to_item_start(self.list.height(), offset);
             // This feels pretty inefficient; but its probably fine.
-            self.list.replace_item(&mut self.cursor, start, &mut self.notify);
+            self.list.replace_item(&mut self.cursor, start, self.notify);
+
+            // Put both halves back before inserting the new content between
+            // them, so the split is reported in one piece (see on_split).
+            self.list.insert_at_iter(&mut self.cursor, &mut iter::once(end), self.notify);
+            self.retreat_item();
 
             // TODO: Consider concatenating end into contents then just call
             // insert_at_iter once.
-            self.list.insert_at_iter(&mut self.cursor, &mut contents, &mut self.notify);
+            self.list.insert_at_iter(&mut self.cursor, &mut contents, self.notify);
 
             self.dbg_check_cursor_at(start_userpos, num_inserted_items);
 
-            self.list.insert_at_iter(&mut self.cursor, &mut iter::once(end), &mut self.notify);
+            // Leave the cursor after the second half.
+            self.advance_item();
         }
+        self.list.save_cursor(&self.cursor);
+    }
+
+    /// Find the num_items items which end skip items before the cursor. The
+    /// cursor is left where it was.
+    fn inserted_before(&mut self, num_items: usize, skip: usize) -> Inserted<Item> {
+        let saved = self.cursor;
+        for _ in 0..num_items + skip {
+            self.retreat_item();
+        }
+
+        let start = self.cursor.userpos;
+        let markers = (0..num_items).map(|_| {
+            self.advance_item();
+            ItemMarker { ptr: self.cursor.here_ptr() }
+        }).collect();
+        let range = start..self.cursor.userpos;
+
+        self.cursor = saved;
+        Inserted { range, markers }
+    }
+
+    /// Like insert_iter, but returns the position of the new items and a
+    /// marker for each of them.
+    pub fn insert_iter_marked<I>(&mut self, contents: I) -> Inserted<Item> where I: ExactSizeIterator<Item=Item> {
+        let num_items = contents.len();
+        self.insert_iter(contents);
+        self.inserted_before(num_items, 0)
+    }
+
+    /// Like insert_between_iter, but returns the position of the new items
+    /// and a marker for each of them.
+    pub fn insert_between_iter_marked<I>(&mut self, offset: usize, contents: I) -> Inserted<Item> where I: ExactSizeIterator<Item=Item> {
+        let num_items = contents.len();
+        self.insert_between_iter(offset, contents);
+        // If we split an item, the cursor is after its second half.
+        self.inserted_before(num_items, if offset > 0 { 1 } else { 0 })
     }
 
     pub fn insert(&mut self, item: Item) {
@@ -77,14 +207,18 @@ impl<'a, Item: ListItem, N: NotifyTarget<Item>> Edit<'a, Item, N> {
         self.insert_iter(items.iter().copied());
     }
 
-    pub fn replace<I>(&mut self, removed_items: usize, mut inserted_content: I)
+    pub fn replace<I>(&mut self, removed_items: usize, inserted_content: I)
     where I: ExactSizeIterator<Item=Item> {
         let num_inserted_items = inserted_content.len();
         let start_userpos = self.cursor.userpos;
-        
-        unsafe { self.list.replace_at_iter(&mut self.cursor, removed_items, &mut inserted_content, &mut self.notify); }
+        let skipped = self.dbg_zero_sized_before();
+        let mut inserted_content = self.record_insert(inserted_content,
+            |items| Op::Replace { pos: start_userpos, removed: removed_items, items });
 
-        self.dbg_check_cursor_at(start_userpos, num_inserted_items);
+        unsafe { self.list.replace_at_iter(&mut self.cursor, removed_items, &mut inserted_content, self.notify); }
+
+        self.dbg_check_cursor_at(start_userpos, skipped + num_inserted_items);
+        self.list.save_cursor(&self.cursor);
     }
 
     pub fn prev_item(&self) -> Option<&Item> {
@@ -109,14 +243,24 @@ impl<'a, Item: ListItem, N: NotifyTarget<Item>> Edit<'a, Item, N> {
         let usersize_delta = new_usersize as isize - old_usersize as isize;
 
         if usersize_delta != 0 {
-            self.cursor.update_offsets(self.list.height(), usersize_delta);
-            self.list.num_usercount = self.list.num_usercount.wrapping_add(usersize_delta as usize);
+            self.list.resize_prev_item(&mut self.cursor, usersize_delta);
+        } else {
+            self.list.bump_version();
         }
 
         self.notify.on_set(std::slice::from_ref(item), ItemMarker {
             ptr: self.cursor.here_ptr(),
             // _phantom: PhantomData,
         });
+        self.list.save_cursor(&self.cursor);
+
+        // The closure can't be logged, so log the item it left behind.
+        let pos = self.cursor.userpos - new_usersize;
+        let item = unsafe { self.cursor.prev_item() }.unwrap();
+        if let Some(recorder) = self.list.recorder.as_mut() {
+            let item = recorder.clone_item(item);
+            recorder.log(Op::Modify { pos, item });
+        }
     }
 
     /// Caveat: This moves the cursor to the next item
@@ -130,12 +274,75 @@ impl<'a, Item: ListItem, N: NotifyTarget<Item>> Edit<'a, Item, N> {
         self.modify_prev_item(|old| *old = replacement);
     }
 
+    /// Move the cursor back one item. Returns false if the cursor is already
+    /// at the start of the list. The cursor must be at an item boundary.
+    pub fn retreat_item(&mut self) -> bool {
+        let prev = match self.prev_item() {
+            Some(item) => item as *const Item,
+            None => return false,
+        };
+        let target = self.cursor.userpos - unsafe { &*prev }.get_usersize();
+
+        // Seeking lands before any zero sized items at target. Step forward
+        // until we're just before the item we started after.
+        let offset = self.list.seek_cursor(&mut self.cursor, target);
+        debug_assert_eq!(offset, 0);
+        let mut skipped = 0;
+        while !ptr::eq(self.current_item().unwrap(), prev) {
+            self.advance_item();
+            skipped += 1;
+        }
+
+        self.dbg_check_cursor_at(target, skipped);
+        true
+    }
+
+    /// Move the cursor back by userlen, returning the offset into the item at
+    /// the new position. This is a finger search, like seek_to.
+    pub fn retreat_by(&mut self, userlen: usize) -> usize {
+        let target = self.cursor.userpos.checked_sub(userlen).expect("Cannot retreat past the start of the list");
+        self.seek_to(target)
+    }
+
+    /// Delete the num_items items before the cursor (ie, backspace). The
+    /// cursor ends up where the deleted items were.
+    pub fn del_backward(&mut self, num_items: usize) {
+        for _ in 0..num_items {
+            assert!(self.retreat_item(), "Cannot delete past the start of the list");
+        }
+        self.del(num_items);
+    }
+
+    /// Move the edit to userpos, forwards or backwards, returning the offset
+    /// into the item there. This searches from the current position, so it
+    /// takes O(log distance) time. Nearby seeks are much cheaper than making
+    /// a new edit.
+    pub fn seek_to(&mut self, userpos: usize) -> usize {
+        let offset = self.list.seek_cursor(&mut self.cursor, userpos);
+        self.dbg_check_cursor_at(userpos, 0);
+        offset
+    }
+
+    /// Finish editing, and tell the notify target the edit is done. Targets
+    /// which buffer notifications (like Batched) deliver them here.
+    pub fn commit(self) {
+        self.notify.flush();
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.notify.flush();
+    }
+
     pub fn user_position(&self) -> usize {
         self.cursor.userpos
     }
+
+    pub(crate) fn len_user(&self) -> usize {
+        self.list.len_user()
+    }
 }
 
-impl<'a, Item: ListItem, N: NotifyTarget<Item>> IntoIterator for Edit<'a, Item, N> {
+impl<'a, Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> IntoIterator for Edit<'a, Item, N, A> {
     type Item = &'a Item;
     type IntoIter = ListItemIter<'a, Item>;
 
@@ -144,14 +351,16 @@ impl<'a, Item: ListItem, N: NotifyTarget<Item>> IntoIterator for Edit<'a, Item,
     }
 }
 
-pub trait SimpleApi<'a, Item: 'a + ListItem, N: 'a + NotifyTarget<Item>> where Self: Sized {
-    fn edit(self, userpos: usize) -> (Edit<'a, Item, N>, usize);
+pub trait SimpleApi<'a, Item: 'a + ListItem, N: 'a + NotifyTarget<Item>, A: 'a + NodeAllocator = Global> where Self: Sized {
+    fn edit(self, userpos: usize) -> (Edit<'a, Item, N, A>, usize);
 
-    fn edit_exact(self, userpos: usize) -> Edit<'a, Item, N>;
+    fn edit_exact(self, userpos: usize) -> Edit<'a, Item, N, A>;
 
 
     fn replace_at<I: ExactSizeIterator<Item=Item>>(self, start_userpos: usize, removed_items: usize, inserted_content: I) {
-        self.edit_exact(start_userpos).replace(removed_items, inserted_content);
+        let mut edit = self.edit_exact(start_userpos);
+        edit.replace(removed_items, inserted_content);
+        edit.commit();
     }
 
     fn replace_at_slice(self, start_userpos: usize, removed_items: usize, inserted_content: &[Item]) where Item: Copy {
@@ -160,46 +369,63 @@ pub trait SimpleApi<'a, Item: 'a + ListItem, N: 'a + NotifyTarget<Item>> where S
 
     fn modify_item_after<F: FnOnce(&mut Item, usize)>(self, userpos: usize, modify_fn: F) {
         let (mut edit, offset) = self.edit(userpos);
-        edit.modify_current_item(|item| modify_fn(item, offset))
+        edit.modify_current_item(|item| modify_fn(item, offset));
+        edit.commit();
     }
 
     fn insert_at<I: ExactSizeIterator<Item=Item>>(self, userpos: usize, contents: I) {
         let (mut edit, offset) = self.edit(userpos);
         edit.insert_between_iter(offset, contents);
+        edit.commit();
     }
 
     fn insert_at_slice(self, userpos: usize, contents: &[Item]) where Item: Copy {
         self.insert_at(userpos, contents.iter().copied())
     }
 
+    /// Like insert_at, but returns the position of the new items and a marker
+    /// for each of them.
+    fn insert_at_marked<I: ExactSizeIterator<Item=Item>>(self, userpos: usize, contents: I) -> Inserted<Item> {
+        let (mut edit, offset) = self.edit(userpos);
+        let inserted = edit.insert_between_iter_marked(offset, contents);
+        edit.commit();
+        inserted
+    }
+
     fn del_at(self, userpos: usize, num_items: usize) {
-        self.edit_exact(userpos).del(num_items)
+        let mut edit = self.edit_exact(userpos);
+        edit.del(num_items);
+        edit.commit();
     }
 }
 
 static mut NULL_NOTIFY_TARGET: () = ();
 
-impl<'a, Item: 'a + ListItem> SimpleApi<'a, Item, ()> for &'a mut SkipList<Item> {
-    fn edit(self, userpos: usize) -> (Edit<'a, Item>, usize) {
-        let (cursor, item_offset) = self.cursor_at_userpos(userpos);
-        (Edit { list: self, cursor, notify: unsafe { &mut NULL_NOTIFY_TARGET } }, item_offset)
+pub(crate) fn null_notify_target<'a>() -> &'a mut () {
+    unsafe { &mut *ptr::addr_of_mut!(NULL_NOTIFY_TARGET) }
+}
+
+impl<'a, Item: 'a + ListItem, A: 'a + NodeAllocator> SimpleApi<'a, Item, (), A> for &'a mut SkipList<Item, (), A> {
+    fn edit(self, userpos: usize) -> (Edit<'a, Item, (), A>, usize) {
+        let (cursor, item_offset) = self.cursor_near(userpos);
+        (Edit { list: self, cursor, notify: null_notify_target() }, item_offset)
     }
 
-    fn edit_exact(self, userpos: usize) -> Edit<'a, Item> {
-        let (cursor, item_offset) = self.cursor_at_userpos(userpos);
+    fn edit_exact(self, userpos: usize) -> Edit<'a, Item, (), A> {
+        let (cursor, item_offset) = self.cursor_near(userpos);
         assert_eq!(item_offset, 0, "edit_between landed inside an item");
-        Edit { list: self, cursor, notify: unsafe { &mut NULL_NOTIFY_TARGET } }
+        Edit { list: self, cursor, notify: null_notify_target() }
     }
 }
 
-impl<'a, Item: 'a + ListItem, N: 'a + NotifyTarget<Item>> SimpleApi<'a, Item, N> for (&'a mut SkipList<Item, N>, &'a mut N) {
-    fn edit(self, userpos: usize) -> (Edit<'a, Item, N>, usize) {
-        let (cursor, item_offset) = self.0.cursor_at_userpos(userpos);
+impl<'a, Item: 'a + ListItem, N: 'a + NotifyTarget<Item>, A: 'a + NodeAllocator> SimpleApi<'a, Item, N, A> for (&'a mut SkipList<Item, N, A>, &'a mut N) {
+    fn edit(self, userpos: usize) -> (Edit<'a, Item, N, A>, usize) {
+        let (cursor, item_offset) = self.0.cursor_near(userpos);
         (Edit { list: self.0, cursor, notify: self.1 }, item_offset)
     }
 
-    fn edit_exact(self, userpos: usize) -> Edit<'a, Item, N> {
-        let (cursor, item_offset) = self.0.cursor_at_userpos(userpos);
+    fn edit_exact(self, userpos: usize) -> Edit<'a, Item, N, A> {
+        let (cursor, item_offset) = self.0.cursor_near(userpos);
         assert_eq!(item_offset, 0, "edit_between landed inside an item");
         Edit { list: self.0, cursor, notify: self.1 }
     }
@@ -207,10 +433,10 @@ impl<'a, Item: 'a + ListItem, N: 'a + NotifyTarget<Item>> SimpleApi<'a, Item, N>
 
 // These methods are only available if there's no notification target.
 impl<Item: ListItem> SkipList<Item> {
+    /// Create a new list containing the items from the iterator. This fills
+    /// nodes in a single O(n) pass, which is much faster than inserting.
     pub fn new_from_iter<I: ExactSizeIterator<Item=Item>>(iter: I) -> Self {
-        let mut list = Self::new();
-        list.insert_at(0, iter);
-        list
+        Self::bulk_load(iter, Global, &mut ())
     }
 
     pub fn new_from_slice(s: &[Item]) -> Self where Item: Copy {
@@ -218,26 +444,49 @@ impl<Item: ListItem> SkipList<Item> {
     }
 }
 
-impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
-    pub fn notify<'a>(&'a mut self, notify: &'a mut N) -> (&'a mut Self, &'a mut N) {
-        (self, notify)
+impl<Item: ListItem, A: NodeAllocator> SkipList<Item, (), A> {
+    /// Create a new list containing the items from the iterator, with nodes
+    /// allocated by the specified allocator.
+    pub fn new_from_iter_in<I: ExactSizeIterator<Item=Item>>(iter: I, alloc: A) -> Self {
+        Self::bulk_load(iter, alloc, &mut ())
     }
 
+    /// Repack all items into evenly filled nodes and regenerate the tower
+    /// heights in a single O(n) pass. This is useful after loading a large
+    /// document, or after lots of edits have left the list fragmented.
+    pub fn compact(&mut self) {
+        self.repack(&mut ());
+    }
+}
+
+impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
+    /// Create a new list containing the items from the iterator. The notify
+    /// target gets one on_set call per node.
     pub fn new_from_iter_n<I: ExactSizeIterator<Item=Item>>(notify: &mut N, iter: I) -> Self {
-        let mut list = Self::new();
-        list.notify(notify).insert_at(0, iter);
-        list
+        Self::bulk_load(iter, Global, notify)
     }
 
     pub fn new_from_slice_n(notify: &mut N, s: &[Item]) -> Self where Item: Copy {
         Self::new_from_iter_n(notify, s.iter().copied())
     }
+}
+
+impl<Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> SkipList<Item, N, A> {
+    pub fn notify<'a>(&'a mut self, notify: &'a mut N) -> (&'a mut Self, &'a mut N) {
+        (self, notify)
+    }
 
-    pub fn edit_n<'a>(&'a mut self, notify: &'a mut N, userpos: usize) -> (Edit<Item, N>, usize) {
+    /// Compact the list. See [`SkipList::compact`]. Every item is moved, so
+    /// every item is re-announced to the notify target with a new marker.
+    pub fn compact_n(&mut self, notify: &mut N) {
+        self.repack(notify);
+    }
+
+    pub fn edit_n<'a>(&'a mut self, notify: &'a mut N, userpos: usize) -> (Edit<'a, Item, N, A>, usize) {
         (self, notify).edit(userpos)
     }
 
-    pub fn edit_between_n<'a>(&'a mut self, notify: &'a mut N, userpos: usize) -> Edit<'a, Item, N> {
+    pub fn edit_between_n<'a>(&'a mut self, notify: &'a mut N, userpos: usize) -> Edit<'a, Item, N, A> {
         (self, notify).edit_exact(userpos)
     }
 
@@ -253,7 +502,7 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
     /// The marker must have been updated using the notifier for the specified
     /// items. If you pass an out of date marker, behaviour is undefined. (It
     /// might segfault.)
-    pub unsafe fn edit_at_marker_exact<'a, P>(&'a mut self, notify: &'a mut N, marker: ItemMarker<Item>, predicate: P) -> Option<Edit<'a, Item, N>>
+    pub unsafe fn edit_at_marker_exact<'a, P>(&'a mut self, notify: &'a mut N, marker: ItemMarker<Item>, predicate: P) -> Option<Edit<'a, Item, N, A>>
     where P: Fn(&Item) -> bool {
         self.cursor_at_marker(marker, |item| if predicate(item) { Some(0) } else { None })
         .map(move |(cursor, item_offset)| {
@@ -275,16 +524,11 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
     /// The marker must have been updated using the notifier for the specified
     /// items. If you pass an out of date marker, behaviour is undefined. (It
     /// might segfault.)
-    pub unsafe fn edit_at_marker<'a, P>(&'a mut self, notify: &'a mut N, marker: ItemMarker<Item>, predicate: P) -> Option<(Edit<'a, Item, N>, usize)>
+    pub unsafe fn edit_at_marker<'a, P>(&'a mut self, notify: &'a mut N, marker: ItemMarker<Item>, predicate: P) -> Option<(Edit<'a, Item, N, A>, usize)>
     where P: Fn(&Item) -> Option<usize> {
         self.cursor_at_marker(marker, predicate)
         .map(move |(cursor, item_offset)| {
             (Edit { list: self, cursor, notify }, item_offset)
         })
     }
-
-    // TODO: We can make an optimized version of this without filling in a cursor.
-    // pub unsafe fn position_at_marker(&self, marker: ItemMarker<Item>, predicate: impl Fn(&Item) -> bool) -> Option<usize> {
-
-    // }
-}
\ No newline at end of file
+}
@@ -0,0 +1,176 @@
+// Batched notifications. A single edit can produce many notifications (one
+// on_set per node touched, and more when items are split or moved). Batched
+// is a notify target which collects them until the edit is committed, then
+// hands them to a BatchTarget in one call with adjacent ranges merged.
+
+use std::ops::{Deref, DerefMut};
+
+use {Edit, ItemMarker, ListItem, NotifyTarget, SkipList};
+use allocator::{NodeAllocator, Global};
+
+/// A change to the list's contents, by position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<Item> {
+    Insert { pos: usize, items: Vec<Item> },
+    Remove { pos: usize, items: Vec<Item> },
+}
+
+/// A change to where items live, in the same terms as on_set / on_delete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkerUpdate<Item: ListItem> {
+    Set { marker: ItemMarker<Item>, items: Vec<Item> },
+    Delete { items: Vec<Item> },
+}
+
+/// The notifications from one edit.
+///
+/// Splits and relocations aren't listed separately. A split shows up as the
+/// removal of the item and the insertion of both halves, and a relocation as
+/// a Set at the new marker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Batch<Item: ListItem> {
+    /// Positional changes, in order. Each change's position is relative to
+    /// the list after the previous changes.
+    pub changes: Vec<Change<Item>>,
+    /// Marker updates, in order.
+    pub markers: Vec<MarkerUpdate<Item>>,
+}
+
+impl<Item: ListItem> Batch<Item> {
+    pub fn new() -> Self {
+        Batch { changes: Vec::new(), markers: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.markers.is_empty()
+    }
+}
+
+impl<Item: ListItem> Default for Batch<Item> {
+    fn default() -> Self { Self::new() }
+}
+
+/// Receives the batches collected by Batched.
+pub trait BatchTarget<Item: ListItem> {
+    fn on_batch(&mut self, batch: Batch<Item>);
+}
+
+impl<Item: ListItem, F: FnMut(Batch<Item>)> BatchTarget<Item> for F {
+    fn on_batch(&mut self, batch: Batch<Item>) { self(batch) }
+}
+
+/// A notify target which buffers notifications and delivers them to target
+/// when flushed. Edits made through SimpleApi flush when they finish; when
+/// using an Edit directly, call Edit::commit when you're done, or use
+/// Batched::edit to get an edit which also flushes when it's dropped.
+#[derive(Debug)]
+pub struct Batched<Item: ListItem, T: BatchTarget<Item>> {
+    target: T,
+    pending: Batch<Item>,
+}
+
+impl<Item: ListItem, T: BatchTarget<Item>> Batched<Item, T> {
+    pub fn new(target: T) -> Self {
+        Batched { target, pending: Batch::new() }
+    }
+
+    /// Notifications which haven't been delivered yet.
+    pub fn pending(&self) -> &Batch<Item> { &self.pending }
+
+    pub fn target(&self) -> &T { &self.target }
+    pub fn target_mut(&mut self) -> &mut T { &mut self.target }
+
+    /// Discard any pending notifications and return the target.
+    pub fn into_inner(self) -> T { self.target }
+}
+
+impl<Item: ListItem + Clone, T: BatchTarget<Item>> Batched<Item, T> {
+    /// Like SkipList::edit_n, but the edit delivers its batch when it's
+    /// dropped as well as when it's committed.
+    pub fn edit<'a, A: NodeAllocator>(&'a mut self, list: &'a mut SkipList<Item, Self, A>, userpos: usize) -> (BatchedEdit<'a, Item, T, A>, usize) {
+        let (edit, offset) = list.edit_n(self, userpos);
+        (BatchedEdit(edit), offset)
+    }
+}
+
+/// An Edit from Batched::edit. It derefs to the Edit.
+pub struct BatchedEdit<'a, Item: ListItem + Clone, T: BatchTarget<Item>, A: NodeAllocator = Global>(Edit<'a, Item, Batched<Item, T>, A>);
+
+impl<'a, Item: ListItem + Clone, T: BatchTarget<Item>, A: NodeAllocator> BatchedEdit<'a, Item, T, A> {
+    /// Finish editing and deliver the batch. This is the same as dropping
+    /// the edit.
+    pub fn commit(self) {}
+}
+
+impl<'a, Item: ListItem + Clone, T: BatchTarget<Item>, A: NodeAllocator> Deref for BatchedEdit<'a, Item, T, A> {
+    type Target = Edit<'a, Item, Batched<Item, T>, A>;
+    fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+impl<'a, Item: ListItem + Clone, T: BatchTarget<Item>, A: NodeAllocator> DerefMut for BatchedEdit<'a, Item, T, A> {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.0 }
+}
+
+impl<'a, Item: ListItem + Clone, T: BatchTarget<Item>, A: NodeAllocator> Drop for BatchedEdit<'a, Item, T, A> {
+    fn drop(&mut self) {
+        // Don't hand a half finished edit to the target while unwinding.
+        if !std::thread::panicking() {
+            self.0.flush();
+        }
+    }
+}
+
+impl<Item: ListItem + Clone, T: BatchTarget<Item>> NotifyTarget<Item> for Batched<Item, T> {
+    fn on_set(&mut self, items: &[Item], at_marker: ItemMarker<Item>) {
+        if let Some(MarkerUpdate::Set { marker, items: prev }) = self.pending.markers.last_mut() {
+            if *marker == at_marker {
+                prev.extend_from_slice(items);
+                return;
+            }
+        }
+        self.pending.markers.push(MarkerUpdate::Set { marker: at_marker, items: items.to_vec() });
+    }
+
+    fn on_delete(&mut self, items: &[Item]) {
+        if let Some(MarkerUpdate::Delete { items: prev }) = self.pending.markers.last_mut() {
+            prev.extend_from_slice(items);
+            return;
+        }
+        self.pending.markers.push(MarkerUpdate::Delete { items: items.to_vec() });
+    }
+
+    fn on_insert(&mut self, userpos: usize, items: &[Item], _at_marker: ItemMarker<Item>) {
+        // Inserts which continue on from the previous insert are merged.
+        if let Some(Change::Insert { pos, items: prev }) = self.pending.changes.last_mut() {
+            if userpos == *pos + Item::userlen_of_slice(prev) {
+                prev.extend_from_slice(items);
+                return;
+            }
+        }
+        self.pending.changes.push(Change::Insert { pos: userpos, items: items.to_vec() });
+    }
+
+    fn on_remove(&mut self, userpos: usize, items: &[Item]) {
+        // Merge removals at the same position (deleting forwards) and
+        // removals which end where the previous one started (deleting
+        // backwards).
+        if let Some(Change::Remove { pos, items: prev }) = self.pending.changes.last_mut() {
+            if userpos == *pos {
+                prev.extend_from_slice(items);
+                return;
+            } else if userpos + Item::userlen_of_slice(items) == *pos {
+                prev.splice(0..0, items.iter().cloned());
+                *pos = userpos;
+                return;
+            }
+        }
+        self.pending.changes.push(Change::Remove { pos: userpos, items: items.to_vec() });
+    }
+
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            let batch = std::mem::take(&mut self.pending);
+            self.target.on_batch(batch);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+// A compact, self-contained binary format for lists.
+//
+// Layout:
+//   magic (4 bytes, "SKPL")
+//   version (1 byte)
+//   number of items (varint)
+//   total usersize of all items (varint)
+//   number of nodes, including the head (varint)
+//   number of items in each node, starting with the head (varints)
+//   items, each written by the caller's ItemCodec
+//
+// Version 1 had no node sizes; those lists are rebuilt with the bulk loader.
+//
+// Decoding puts the items back into nodes of the same sizes. Tower heights
+// aren't stored, and are picked again as the nodes are made. NODE_NUM_ITEMS
+// differs between debug and release builds, so if a saved node is too big for
+// this build the layout is ignored and the list is bulk loaded instead.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::mem;
+
+use allocator::{NodeAllocator, Global};
+use skiplist::NODE_NUM_ITEMS;
+use {ListItem, NotifyTarget, SkipList};
+
+const MAGIC: &[u8; 4] = b"SKPL";
+const VERSION: u8 = 2;
+
+/// Reads and writes individual items for encode / decode.
+pub trait ItemCodec<Item> {
+    fn encode_item<W: Write>(&self, item: &Item, w: &mut W) -> io::Result<()>;
+    fn decode_item<R: Read>(&self, r: &mut R) -> io::Result<Item>;
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(io::Error),
+    /// The data doesn't start with the expected magic bytes.
+    BadMagic,
+    /// The data was written by an unknown version of the format.
+    UnsupportedVersion(u8),
+    /// A varint in the header doesn't fit in a usize.
+    BadVarint,
+    /// The decoded usersize doesn't match the total in the header.
+    UsercountMismatch { expected: usize, actual: usize },
+    /// The items' usersizes add up to more than fits in a usize.
+    UsercountOverflow,
+    /// The node sizes don't add up to the number of items, or a node other
+    /// than the head is empty.
+    BadLayout,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "io error: {}", e),
+            DecodeError::BadMagic => write!(f, "not an encoded skip list"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+            DecodeError::BadVarint => write!(f, "invalid varint"),
+            DecodeError::UsercountMismatch { expected, actual } =>
+                write!(f, "usercount mismatch: expected {}, decoded {}", expected, actual),
+            DecodeError::UsercountOverflow => write!(f, "usercount overflows usize"),
+            DecodeError::BadLayout => write!(f, "invalid node sizes"),
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DecodeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self { DecodeError::Io(e) }
+}
+
+fn write_varint<W: Write>(w: &mut W, mut val: usize) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    w.write_all(&buf[..len])
+}
+
+fn read_varint<R: Read>(r: &mut R) -> Result<usize, DecodeError> {
+    let mut val: usize = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8];
+        r.read_exact(&mut byte)?;
+        let bits = (byte[0] & 0x7f) as usize;
+        if shift >= mem::size_of::<usize>() * 8 || (bits << shift) >> shift != bits {
+            return Err(DecodeError::BadVarint);
+        }
+        val |= bits << shift;
+        if byte[0] & 0x80 == 0 { return Ok(val); }
+        shift += 7;
+    }
+}
+
+// Adapts a fixed number of codec reads into an iterator for the bulk loader.
+// The first error ends the iteration and is stashed for the caller. Usersizes
+// are totalled here with overflow checks, so the loader's sums can't overflow.
+struct DecodeIter<'a, Item, R: Read, C> {
+    r: &'a mut R,
+    codec: &'a C,
+    remaining: usize,
+    usercount: usize,
+    err: Option<DecodeError>,
+    _phantom: PhantomData<fn() -> Item>,
+}
+
+impl<'a, Item: ListItem, R: Read, C: ItemCodec<Item>> Iterator for DecodeIter<'a, Item, R, C> {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Item> {
+        if self.remaining == 0 || self.err.is_some() { return None; }
+        match self.codec.decode_item(self.r) {
+            Ok(item) => match self.usercount.checked_add(item.get_usersize()) {
+                Some(usercount) => {
+                    self.usercount = usercount;
+                    self.remaining -= 1;
+                    Some(item)
+                }
+                None => { self.err = Some(DecodeError::UsercountOverflow); None }
+            }
+            Err(e) => { self.err = Some(e.into()); None }
+        }
+    }
+}
+
+fn read_layout<R: Read>(r: &mut R, num_items: usize) -> Result<Vec<usize>, DecodeError> {
+    let num_nodes = read_varint(r)?;
+    // Every node but the head holds at least one item.
+    if num_nodes == 0 || num_nodes - 1 > num_items { return Err(DecodeError::BadLayout); }
+
+    // Not preallocated, since num_nodes hasn't been checked against the
+    // length of the input.
+    let mut sizes = Vec::new();
+    let mut total: usize = 0;
+    for i in 0..num_nodes {
+        let size = read_varint(r)?;
+        if i > 0 && size == 0 { return Err(DecodeError::BadLayout); }
+        total = total.checked_add(size).ok_or(DecodeError::BadLayout)?;
+        sizes.push(size);
+    }
+    if total != num_items { return Err(DecodeError::BadLayout); }
+    Ok(sizes)
+}
+
+impl<Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> SkipList<Item, N, A> {
+    /// Write the list's contents in the binary format described in
+    /// encoding.rs, using codec to write each item.
+    pub fn encode<W: Write, C: ItemCodec<Item>>(&self, w: &mut W, codec: &C) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        write_varint(w, self.len_items())?;
+        write_varint(w, self.len_user())?;
+        write_varint(w, self.node_sizes().count())?;
+        for size in self.node_sizes() {
+            write_varint(w, size)?;
+        }
+        for item in self.iter() {
+            codec.encode_item(item, w)?;
+        }
+        Ok(())
+    }
+}
+
+impl<Item: ListItem> SkipList<Item> {
+    /// Read a list written by encode. Corrupted or truncated input returns an
+    /// error rather than panicking.
+    pub fn decode<R: Read, C: ItemCodec<Item>>(r: R, codec: &C) -> Result<Self, DecodeError> {
+        Self::decode_in(r, codec, Global)
+    }
+}
+
+impl<Item: ListItem, A: NodeAllocator> SkipList<Item, (), A> {
+    /// Read a list written by encode, allocating nodes from alloc.
+    pub fn decode_in<R: Read, C: ItemCodec<Item>>(mut r: R, codec: &C, alloc: A) -> Result<Self, DecodeError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC { return Err(DecodeError::BadMagic); }
+
+        let mut version = [0u8];
+        r.read_exact(&mut version)?;
+        if version[0] != 1 && version[0] != VERSION { return Err(DecodeError::UnsupportedVersion(version[0])); }
+
+        let num_items = read_varint(&mut r)?;
+        let usercount = read_varint(&mut r)?;
+        let layout = if version[0] == 1 { None } else { Some(read_layout(&mut r, num_items)?) };
+
+        let mut iter = DecodeIter { r: &mut r, codec, remaining: num_items, usercount: 0, err: None, _phantom: PhantomData };
+        let list = match layout {
+            Some(sizes) if sizes.iter().all(|&size| size <= NODE_NUM_ITEMS) =>
+                SkipList::bulk_load_with_layout(&mut iter, &sizes, alloc),
+            _ => SkipList::bulk_load(&mut iter, alloc, &mut ()),
+        };
+        if let Some(e) = iter.err { return Err(e); }
+
+        if list.len_user() != usercount {
+            return Err(DecodeError::UsercountMismatch { expected: usercount, actual: list.len_user() });
+        }
+        Ok(list)
+    }
+}
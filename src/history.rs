@@ -0,0 +1,163 @@
+// Undo / redo support. Edits are made as Ops through a History, which
+// captures the items each op is about to remove and stores the inverse op.
+// Undoing applies the inverse ops through the list's notify target, so
+// markers stay consistent.
+//
+// The inverse ops are only right for the list as the History left it. It
+// remembers the list's version after each edit, undo and redo, and refuses to
+// go on if the list has been edited some other way since.
+
+use {ListItem, NotifyTarget, SkipList, Op};
+use allocator::NodeAllocator;
+
+#[derive(Debug, Clone)]
+struct Entry<Item> {
+    op: Op<Item>,
+    inverse: Op<Item>,
+}
+
+/// An undo / redo stack for a list. Consecutive edits can be grouped with
+/// begin_group / end_group, and are then undone and redone together.
+#[derive(Debug, Clone)]
+pub struct History<Item> {
+    undo: Vec<Vec<Entry<Item>>>,
+    redo: Vec<Vec<Entry<Item>>>,
+    open: Vec<Entry<Item>>,
+    depth: usize,
+    /// The list's version after the last edit made through this history.
+    version: Option<usize>,
+}
+
+impl<Item: ListItem + Clone, N: NotifyTarget<Item>, A: NodeAllocator> SkipList<Item, N, A> {
+    /// Clone count items starting at userpos.
+    pub(crate) fn clone_items_at(&self, userpos: usize, count: usize) -> Vec<Item> {
+        let (cursor, offset) = self.cursor_at_userpos(userpos);
+        assert_eq!(offset, 0, "Position is inside an item");
+        unsafe { &*cursor.here_ptr() }.iter(cursor.local_index).take(count).cloned().collect()
+    }
+
+    /// Work out the op which undoes op, given the list's current contents.
+    pub(crate) fn invert_op(&self, op: &Op<Item>) -> Op<Item> {
+        match op {
+            Op::Insert { pos, items } => {
+                let (cursor, offset) = self.cursor_at_userpos(*pos);
+                if offset == 0 {
+                    Op::Delete { pos: *pos, count: items.len() }
+                } else {
+                    // The insert splits the item containing pos. Undoing it
+                    // removes both halves along with the new items, and puts
+                    // the original item back.
+                    let item = unsafe { cursor.current_item() }.unwrap().clone();
+                    Op::Replace { pos: *pos - offset, removed: items.len() + 2, items: vec![item] }
+                }
+            }
+            Op::Delete { pos, count } => Op::Insert { pos: *pos, items: self.clone_items_at(*pos, *count) },
+            Op::Replace { pos, removed, items } => Op::Replace {
+                pos: *pos,
+                removed: items.len(),
+                items: self.clone_items_at(*pos, *removed),
+            },
+            Op::Modify { pos, .. } => {
+                let (cursor, offset) = self.cursor_at_userpos(*pos);
+                let mut cursor = cursor;
+                cursor.advance_item(self.height());
+                let old = unsafe { cursor.prev_item() }.expect("Cannot modify past the end of the list").clone();
+                Op::Modify { pos: *pos - offset, item: old }
+            }
+        }
+    }
+}
+
+impl<Item: ListItem + Clone> History<Item> {
+    pub fn new() -> Self {
+        History { undo: Vec::new(), redo: Vec::new(), open: Vec::new(), depth: 0, version: None }
+    }
+
+    fn check_version<N: NotifyTarget<Item>, A: NodeAllocator>(&self, list: &SkipList<Item, N, A>) {
+        if let Some(version) = self.version {
+            assert_eq!(version, list.version, "List was edited outside the history");
+        }
+    }
+
+    /// Apply an edit to the list and record it. This clears the redo stack.
+    pub fn apply<A: NodeAllocator>(&mut self, list: &mut SkipList<Item, (), A>, op: Op<Item>) {
+        self.apply_n(list, &mut (), op);
+    }
+
+    ///
+    /// Panics if the list was edited some other way since the history last
+    /// touched it, since the recorded edits could no longer be undone. The
+    /// same goes for undo and redo.
+    pub fn apply_n<N: NotifyTarget<Item>, A: NodeAllocator>(&mut self, list: &mut SkipList<Item, N, A>, notify: &mut N, op: Op<Item>) {
+        self.check_version(list);
+        let inverse = list.invert_op(&op);
+        list.apply_n(notify, &op);
+        self.version = Some(list.version);
+        self.redo.clear();
+        self.open.push(Entry { op, inverse });
+        if self.depth == 0 { self.close_group(); }
+    }
+
+    /// Start a group of edits. Groups nest; the edits are committed as one
+    /// undo step when the outermost group ends.
+    pub fn begin_group(&mut self) {
+        self.depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        assert!(self.depth > 0, "end_group called without begin_group");
+        self.depth -= 1;
+        if self.depth == 0 { self.close_group(); }
+    }
+
+    fn close_group(&mut self) {
+        if !self.open.is_empty() {
+            self.undo.push(std::mem::take(&mut self.open));
+        }
+    }
+
+    pub fn can_undo(&self) -> bool { !self.undo.is_empty() }
+    pub fn can_redo(&self) -> bool { !self.redo.is_empty() }
+
+    /// Undo the most recent group of edits. Returns false if there was nothing
+    /// to undo.
+    pub fn undo<A: NodeAllocator>(&mut self, list: &mut SkipList<Item, (), A>) -> bool {
+        self.undo_n(list, &mut ())
+    }
+
+    pub fn undo_n<N: NotifyTarget<Item>, A: NodeAllocator>(&mut self, list: &mut SkipList<Item, N, A>, notify: &mut N) -> bool {
+        assert_eq!(self.depth, 0, "Cannot undo inside a group");
+        if let Some(group) = self.undo.pop() {
+            self.check_version(list);
+            for entry in group.iter().rev() {
+                list.apply_n(notify, &entry.inverse);
+            }
+            self.version = Some(list.version);
+            self.redo.push(group);
+            true
+        } else { false }
+    }
+
+    /// Redo the most recently undone group of edits. Returns false if there
+    /// was nothing to redo.
+    pub fn redo<A: NodeAllocator>(&mut self, list: &mut SkipList<Item, (), A>) -> bool {
+        self.redo_n(list, &mut ())
+    }
+
+    pub fn redo_n<N: NotifyTarget<Item>, A: NodeAllocator>(&mut self, list: &mut SkipList<Item, N, A>, notify: &mut N) -> bool {
+        assert_eq!(self.depth, 0, "Cannot redo inside a group");
+        if let Some(group) = self.redo.pop() {
+            self.check_version(list);
+            for entry in group.iter() {
+                list.apply_n(notify, &entry.op);
+            }
+            self.version = Some(list.version);
+            self.undo.push(group);
+            true
+        } else { false }
+    }
+}
+
+impl<Item: ListItem + Clone> Default for History<Item> {
+    fn default() -> Self { Self::new() }
+}
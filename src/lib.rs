@@ -1,11 +1,37 @@
 extern crate rand;
+#[cfg(feature = "serde")]
+extern crate serde;
 
 // mod rope;
 // pub use rope::{Rope, RopeError};
 
 // mod edittablestr;
+mod allocator;
+pub use allocator::{NodeAllocator, Global};
 mod skiplist;
 pub use skiplist::*;
 mod api;
-pub use api::Edit;
-pub use api::SimpleApi;
\ No newline at end of file
+pub use api::{Edit, Inserted};
+pub use api::SimpleApi;
+mod marker_index;
+pub use marker_index::{KeyedItem, MarkerIndex};
+mod anchor;
+pub use anchor::{Anchor, Anchors, Bias};
+mod annotations;
+pub use annotations::{Annotations, AnnotationId};
+mod batch;
+pub use batch::{Batch, Batched, BatchedEdit, BatchTarget, Change, MarkerUpdate};
+mod ops;
+pub use ops::{Op, Recorder};
+mod history;
+pub use history::History;
+mod transaction;
+pub use transaction::Transaction;
+mod multi_edit;
+pub use multi_edit::MultiEdit;
+mod encoding;
+pub use encoding::{ItemCodec, DecodeError};
+mod shared;
+pub use shared::{SharedList, ListReader, ReadGuard};
+#[cfg(feature = "serde")]
+mod serde_impl;
\ No newline at end of file
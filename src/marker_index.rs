@@ -0,0 +1,86 @@
+// A ready-made notify target which tracks a marker for every item in the
+// list, keyed by something in the item. This is what most notify targets end
+// up doing by hand.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use {Edit, ItemMarker, ListItem, NotifyTarget, SkipList};
+use allocator::NodeAllocator;
+
+/// Items which can be looked up by key in a MarkerIndex. Keys must be unique
+/// across the list. (Watch out for split_item, which shouldn't produce two
+/// items with the same key.)
+pub trait KeyedItem<K>: ListItem {
+    fn key(&self) -> K;
+}
+
+/// Maps item keys to markers. Use this as the list's notify target.
+#[derive(Debug)]
+pub struct MarkerIndex<K, Item: ListItem> {
+    markers: HashMap<K, ItemMarker<Item>>,
+}
+
+impl<K: Hash + Eq, Item: KeyedItem<K>> NotifyTarget<Item> for MarkerIndex<K, Item> {
+    fn on_set(&mut self, items: &[Item], at_marker: ItemMarker<Item>) {
+        for item in items {
+            self.markers.insert(item.key(), at_marker);
+        }
+    }
+
+    fn on_delete(&mut self, items: &[Item]) {
+        for item in items {
+            self.markers.remove(&item.key());
+        }
+    }
+
+    fn on_remove(&mut self, _userpos: usize, items: &[Item]) {
+        // Replaced items get on_remove but not on_delete.
+        for item in items {
+            self.markers.remove(&item.key());
+        }
+    }
+}
+
+impl<K: Hash + Eq, Item: KeyedItem<K>> MarkerIndex<K, Item> {
+    pub fn new() -> Self {
+        MarkerIndex { markers: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize { self.markers.len() }
+    pub fn is_empty(&self) -> bool { self.markers.is_empty() }
+
+    pub fn contains_key<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> bool where K: Borrow<Q> {
+        self.markers.contains_key(key)
+    }
+
+    /// Get the marker for the item with the specified key.
+    pub fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<ItemMarker<Item>> where K: Borrow<Q> {
+        self.markers.get(key).copied()
+    }
+
+    /// Find the position of the item with the specified key.
+    ///
+    /// # Safety
+    /// This index must have been the notify target for every change made to
+    /// list. Otherwise the markers could point anywhere.
+    pub unsafe fn position_of<A: NodeAllocator>(&self, list: &SkipList<Item, Self, A>, key: &K) -> Option<usize> {
+        let marker = self.get(key)?;
+        list.userpos_at_marker(marker, |item| item.key() == *key)
+    }
+
+    /// Get an edit object pointing at the item with the specified key.
+    ///
+    /// # Safety
+    /// This index must have been the notify target for every change made to
+    /// list. Otherwise the markers could point anywhere.
+    pub unsafe fn edit_at<'a, A: NodeAllocator>(&'a mut self, list: &'a mut SkipList<Item, Self, A>, key: &K) -> Option<Edit<'a, Item, Self, A>> {
+        let marker = self.get(key)?;
+        list.edit_at_marker_exact(self, marker, |item| item.key() == *key)
+    }
+}
+
+impl<K: Hash + Eq, Item: KeyedItem<K>> Default for MarkerIndex<K, Item> {
+    fn default() -> Self { Self::new() }
+}
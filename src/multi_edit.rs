@@ -0,0 +1,131 @@
+// Editing at several positions at once, like the carets in a multi-cursor
+// editor. Every edit is applied at each caret in a single left to right pass
+// with one Edit, and the carets after each edit are shifted to match.
+
+use {Edit, ListItem, NotifyTarget, SkipList, SimpleApi};
+use allocator::{NodeAllocator, Global};
+use api::null_notify_target;
+
+/// Applies the same edit at each of a set of carets. Carets are kept sorted,
+/// and carets which end up at the same position are merged.
+pub struct MultiEdit<'a, Item: ListItem, N: NotifyTarget<Item> = (), A: NodeAllocator = Global> {
+    edit: Edit<'a, Item, N, A>,
+    carets: Vec<usize>,
+}
+
+impl<'a, Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> MultiEdit<'a, Item, N, A> {
+    fn new(edit: Edit<'a, Item, N, A>, mut carets: Vec<usize>) -> Self {
+        carets.sort_unstable();
+        carets.dedup();
+        MultiEdit { edit, carets }
+    }
+
+    /// The current caret positions, in order.
+    pub fn carets(&self) -> &[usize] { &self.carets }
+
+    /// Insert a copy of items at each caret. Each caret moves to the end of
+    /// its copy.
+    pub fn insert_slice(&mut self, items: &[Item]) where Item: Clone {
+        let len = Item::userlen_of_slice(items);
+        let mut shift = 0;
+        for caret in &mut self.carets {
+            let offset = self.edit.seek_to(*caret + shift);
+            self.edit.insert_between_iter(offset, items.iter().cloned());
+            shift += len;
+            *caret += shift;
+        }
+    }
+
+    /// Delete up to num_items items after each caret. A delete stops early
+    /// rather than run into the next caret. Carets must be at item
+    /// boundaries.
+    pub fn del(&mut self, num_items: usize) {
+        let mut removed = 0;
+        for i in 0..self.carets.len() {
+            let pos = self.carets[i] - removed;
+            let limit = self.carets.get(i + 1).map_or(usize::MAX, |next| next - removed);
+            let offset = self.edit.seek_to(pos);
+            assert_eq!(offset, 0, "Cannot delete from the middle of an item");
+
+            // Find how many items we can delete before hitting the next caret.
+            let mut count = 0;
+            let mut end = pos;
+            while count < num_items {
+                match self.edit.current_item().map(|item| item.get_usersize()) {
+                    Some(size) if end + size <= limit => {
+                        self.edit.advance_item();
+                        end += size;
+                        count += 1;
+                    }
+                    _ => break,
+                }
+            }
+
+            if count > 0 {
+                self.edit.seek_to(pos);
+                self.edit.del(count);
+            }
+            self.carets[i] = pos;
+            removed += end - pos;
+        }
+        self.carets.dedup();
+    }
+
+    /// Delete up to num_items items before each caret, like pressing
+    /// backspace. A delete stops early rather than run into the previous
+    /// caret. Carets must be at item boundaries.
+    pub fn del_backward(&mut self, num_items: usize) {
+        let mut removed = 0;
+        let mut limit = 0;
+        for i in 0..self.carets.len() {
+            let pos = self.carets[i] - removed;
+            let offset = self.edit.seek_to(pos);
+            assert_eq!(offset, 0, "Cannot delete from the middle of an item");
+
+            let mut count = 0;
+            let mut start = pos;
+            while count < num_items {
+                match self.edit.prev_item().map(|item| item.get_usersize()) {
+                    Some(size) if start >= limit + size => {
+                        self.edit.retreat_item();
+                        start -= size;
+                        count += 1;
+                    }
+                    _ => break,
+                }
+            }
+
+            if count > 0 { self.edit.del(count); }
+            self.carets[i] = start;
+            removed += pos - start;
+            limit = start;
+        }
+        self.carets.dedup();
+    }
+
+    /// Finish editing. Returns the final caret positions.
+    pub fn commit(self) -> Vec<usize> {
+        self.edit.commit();
+        self.carets
+    }
+}
+
+impl<Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> SkipList<Item, N, A> {
+    /// Start editing at each of carets at once. The carets don't need to be
+    /// sorted.
+    pub fn multi_edit_n<'a>(&'a mut self, notify: &'a mut N, carets: Vec<usize>) -> MultiEdit<'a, Item, N, A> {
+        let (edit, _) = self.edit_n(notify, 0);
+        MultiEdit::new(edit, carets)
+    }
+}
+
+impl<Item: ListItem, A: NodeAllocator> SkipList<Item, (), A> {
+    /// Start editing at each of carets at once. The carets don't need to be
+    /// sorted, and duplicates are merged. Carets may be inside items for
+    /// inserts (which split the items), but deletes need them at item
+    /// boundaries.
+    pub fn multi_edit(&mut self, carets: Vec<usize>) -> MultiEdit<'_, Item, (), A> {
+        let (edit, _) = (self, null_notify_target()).edit(0);
+        MultiEdit::new(edit, carets)
+    }
+}
@@ -0,0 +1,191 @@
+// Edits as data. An Op describes one SimpleApi call, so edits can be logged,
+// sent elsewhere and replayed. While a list is being recorded, every Edit logs
+// what it does as an Op in the list's Recorder, along with the list's RNG
+// seed, contents and node layout, so a session can be reproduced exactly.
+
+use {ListItem, NotifyTarget, SkipList, SimpleApi};
+use allocator::{NodeAllocator, Global};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op<Item> {
+    /// Insert items at pos. If pos is inside an item, the item is split.
+    Insert { pos: usize, items: Vec<Item> },
+    /// Delete count items starting at pos.
+    Delete { pos: usize, count: usize },
+    /// Delete removed items starting at pos, and insert items in their place.
+    Replace { pos: usize, removed: usize, items: Vec<Item> },
+    /// Overwrite the item containing pos with item.
+    Modify { pos: usize, item: Item },
+}
+
+impl<Item: ListItem + Clone, N: NotifyTarget<Item>, A: NodeAllocator> SkipList<Item, N, A> {
+    /// Apply an operation to the list, calling notify as items change.
+    pub fn apply_n(&mut self, notify: &mut N, op: &Op<Item>) {
+        let list = (self, notify);
+        match op {
+            Op::Insert { pos, items } => list.insert_at(*pos, items.iter().cloned()),
+            Op::Delete { pos, count } => list.del_at(*pos, *count),
+            Op::Replace { pos, removed, items } => list.replace_at(*pos, *removed, items.iter().cloned()),
+            Op::Modify { pos, item } => {
+                let (mut edit, _) = list.edit(*pos);
+                edit.modify_current_item(|i| *i = item.clone());
+                edit.commit();
+            }
+        }
+    }
+
+    /// Apply a batch of operations sorted by position. Positions refer to the
+    /// list as it was before the batch, so a patch can be built from a single
+    /// view of the document. Each op is shifted by the length inserted and
+    /// removed by the ops before it.
+    ///
+    /// Ops mustn't overlap: each op has to start at or after the end of the
+    /// items removed by the op before it. Several inserts at the same
+    /// position are applied in order.
+    ///
+    /// The batch is applied with a single cursor which only moves forwards,
+    /// so the list is walked once instead of searched from the head for each
+    /// op.
+    pub fn apply_batch_n(&mut self, notify: &mut N, ops: &[Op<Item>]) {
+        let (mut edit, _) = self.edit_n(notify, 0);
+        // How much the earlier ops have grown (or shrunk) the list, and where
+        // the last one ended. Both are in the original list's positions.
+        let mut shift: isize = 0;
+        let mut end = 0;
+
+        for op in ops {
+            let pos = match op {
+                Op::Insert { pos, .. } | Op::Delete { pos, .. }
+                    | Op::Replace { pos, .. } | Op::Modify { pos, .. } => *pos
+            };
+            assert!(pos >= end, "Batch ops must be sorted by position and must not overlap");
+            let len_before = edit.len_user();
+            let offset = edit.seek_to((pos as isize + shift) as usize);
+
+            let inserted = match op {
+                Op::Insert { items, .. } => {
+                    edit.insert_between_iter(offset, items.iter().cloned());
+                    Item::userlen_of_slice(items)
+                }
+                Op::Delete { count, .. } => {
+                    assert_eq!(offset, 0, "Cannot delete from the middle of an item");
+                    edit.del(*count);
+                    0
+                }
+                Op::Replace { removed, items, .. } => {
+                    assert_eq!(offset, 0, "Cannot replace from the middle of an item");
+                    edit.replace(*removed, items.iter().cloned());
+                    Item::userlen_of_slice(items)
+                }
+                Op::Modify { item, .. } => {
+                    edit.modify_current_item(|i| *i = item.clone());
+                    0
+                }
+            };
+
+            let growth = edit.len_user() as isize - len_before as isize;
+            if let Op::Delete { .. } | Op::Replace { .. } = op {
+                end = pos + (inserted as isize - growth) as usize;
+            } else {
+                end = pos;
+            }
+            shift += growth;
+        }
+        edit.commit();
+    }
+}
+
+impl<Item: ListItem + Clone, A: NodeAllocator> SkipList<Item, (), A> {
+    /// Apply an operation to the list.
+    pub fn apply(&mut self, op: &Op<Item>) {
+        self.apply_n(&mut (), op);
+    }
+
+    /// Apply a batch of operations sorted by position, using a single cursor.
+    /// Positions refer to the list as it was before the batch. See
+    /// apply_batch_n.
+    pub fn apply_batch(&mut self, ops: &[Op<Item>]) {
+        self.apply_batch_n(&mut (), ops);
+    }
+}
+
+impl<Item: ListItem + Clone, N: NotifyTarget<Item>, A: NodeAllocator> SkipList<Item, N, A> {
+    /// Start logging every edit made to the list. This seeds the list's RNG,
+    /// and notes the list's contents and node layout, so the log can be
+    /// replayed to an identical list. Any earlier recording is discarded.
+    pub fn start_recording(&mut self, seed: u64) {
+        self.init_rng_from_seed(seed);
+        self.recorder = Some(Box::new(Recorder {
+            seed,
+            base: self.iter().cloned().collect(),
+            layout: self.node_sizes().collect(),
+            ops: Vec::new(),
+            clone_item: Item::clone,
+        }));
+    }
+
+    /// The log so far, if the list is being recorded.
+    pub fn recorder(&self) -> Option<&Recorder<Item>> {
+        self.recorder.as_deref()
+    }
+
+    /// Stop recording, and return the log.
+    pub fn stop_recording(&mut self) -> Option<Recorder<Item>> {
+        self.recorder.take().map(|recorder| *recorder)
+    }
+}
+
+/// A log of edits made to a list, along with the seed of the list's RNG and
+/// its contents and node layout when recording started. See
+/// SkipList::start_recording.
+///
+/// Every edit made through an Edit is logged, and so everything built on
+/// one: SimpleApi, apply, History, Transaction and MultiEdit. compact()
+/// isn't logged, so the replayed list's layout won't match after it. Nor
+/// can an Op say which side of a zero sized item an edit was made, so edits
+/// next to zero sized items may replay on the other side of them.
+#[derive(Debug, Clone)]
+pub struct Recorder<Item> {
+    seed: u64,
+    base: Vec<Item>,
+    /// The number of items in each node when recording started.
+    layout: Vec<usize>,
+    ops: Vec<Op<Item>>,
+    /// Edit doesn't need Item: Clone, so the recorder brings its own.
+    clone_item: fn(&Item) -> Item,
+}
+
+impl<Item> Recorder<Item> {
+    pub(crate) fn log(&mut self, op: Op<Item>) {
+        self.ops.push(op);
+    }
+
+    pub(crate) fn clone_item(&self, item: &Item) -> Item {
+        (self.clone_item)(item)
+    }
+
+    pub(crate) fn clone_items(&self, items: &[Item]) -> Vec<Item> {
+        items.iter().map(self.clone_item).collect()
+    }
+
+    pub fn seed(&self) -> u64 { self.seed }
+
+    /// The list's contents when recording started.
+    pub fn base(&self) -> &[Item] { &self.base }
+
+    pub fn ops(&self) -> &[Op<Item>] { &self.ops }
+}
+
+impl<Item: ListItem + Clone> Recorder<Item> {
+    /// Replay the log into a new list. The list starts out with the same
+    /// nodes as the recorded one, and is then seeded and edited the same
+    /// way, so it ends up with the same layout.
+    pub fn replay(&self) -> SkipList<Item> {
+        let mut list = SkipList::bulk_load_with_layout(self.base.iter().cloned(), &self.layout, Global);
+        list.init_rng_from_seed(self.seed);
+        for op in &self.ops {
+            list.apply(op);
+        }
+        list
+    }
+}
@@ -0,0 +1,69 @@
+// Serde support, behind the serde feature. Lists serialize as a plain
+// sequence of items, and deserialize through the bulk loader.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer, SerializeSeq};
+
+use allocator::NodeAllocator;
+use {ListItem, NotifyTarget, SkipList};
+
+impl<Item, N, A> Serialize for SkipList<Item, N, A>
+        where Item: ListItem + Serialize, N: NotifyTarget<Item>, A: NodeAllocator {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len_items()))?;
+        for item in self.iter() {
+            seq.serialize_element(item)?;
+        }
+        seq.end()
+    }
+}
+
+// Adapts a SeqAccess into an iterator for the bulk loader. The first error
+// ends the iteration and is stashed for the caller.
+struct SeqIter<'a, S: SeqAccess<'a>, Item> {
+    seq: S,
+    err: Option<S::Error>,
+    _phantom: PhantomData<fn() -> Item>,
+}
+
+impl<'a, S: SeqAccess<'a>, Item: Deserialize<'a>> Iterator for SeqIter<'a, S, Item> {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Item> {
+        if self.err.is_some() { return None; }
+        match self.seq.next_element() {
+            Ok(item) => item,
+            Err(e) => { self.err = Some(e); None }
+        }
+    }
+}
+
+struct ListVisitor<Item, A>(PhantomData<(Item, A)>);
+
+impl<'de, Item, A> Visitor<'de> for ListVisitor<Item, A>
+        where Item: ListItem + Deserialize<'de>, A: NodeAllocator + Default {
+    type Value = SkipList<Item, (), A>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a sequence of list items")
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, seq: S) -> Result<Self::Value, S::Error> {
+        let mut iter = SeqIter { seq, err: None, _phantom: PhantomData };
+        let list = SkipList::bulk_load(&mut iter, A::default(), &mut ());
+        match iter.err {
+            Some(e) => Err(e),
+            None => Ok(list),
+        }
+    }
+}
+
+impl<'de, Item, A> Deserialize<'de> for SkipList<Item, (), A>
+        where Item: ListItem + Deserialize<'de>, A: NodeAllocator + Default {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(ListVisitor(PhantomData))
+    }
+}
@@ -0,0 +1,250 @@
+// A single writer / many readers wrapper around SkipList.
+//
+// Nodes can't be shared between lists, so readers can't walk the list while
+// it's being edited. Instead the writer edits its own copy, and publishing
+// swaps that in as the version readers see. Readers read the published
+// version without taking any locks.
+//
+// Publishing never waits for readers. The version it replaces is retired,
+// along with a note of which readers were mid-read at the time, and it's
+// freed once all of them have moved on (this is epoch based reclamation). The
+// writer's next edit needs a new copy to work on. If the version retired by
+// the last publish isn't being read any more, the writer takes it back and
+// catches it up by replaying the edits logged since it was current. That's
+// O(edits), so the writer pays for each edit twice rather than copying the
+// list on every publish. If a reader is still in it, the writer copies the
+// published version instead, which is O(n).
+//
+// Each reader has an epoch counter, which is odd while it's reading.
+
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering::SeqCst};
+
+use allocator::{NodeAllocator, Global};
+use {ListItem, Op, SkipList};
+
+struct Inner<Item: ListItem, A: NodeAllocator> {
+    /// The published version. Only the writer changes it.
+    current: AtomicPtr<SkipList<Item, (), A>>,
+    /// The epoch counter of every live reader.
+    epochs: Mutex<Vec<Arc<AtomicUsize>>>,
+    /// Versions replaced by publish, oldest first.
+    retired: Mutex<Vec<Retired<Item, A>>>,
+}
+
+struct Retired<Item: ListItem, A: NodeAllocator> {
+    list: Box<SkipList<Item, (), A>>,
+    /// The readers which were reading when this was retired, and their
+    /// epochs at the time. They might still be reading it.
+    readers: Vec<(Arc<AtomicUsize>, usize)>,
+}
+
+impl<Item: ListItem, A: NodeAllocator> Retired<Item, A> {
+    fn in_use(&self) -> bool {
+        self.readers.iter().any(|(epoch, e)| epoch.load(SeqCst) == *e)
+    }
+}
+
+// Readers only ever take shared references to the list current points to.
+// The writer never edits a list once it's been published, and only frees or
+// reuses a retired list when every reader which might have seen it has
+// finished reading.
+unsafe impl<Item, A> Send for Inner<Item, A>
+    where Item: ListItem + Send + Sync, A: NodeAllocator + Send + Sync {}
+unsafe impl<Item, A> Sync for Inner<Item, A>
+    where Item: ListItem + Send + Sync, A: NodeAllocator + Send + Sync {}
+
+impl<Item: ListItem, A: NodeAllocator> Drop for Inner<Item, A> {
+    fn drop(&mut self) {
+        // Nobody else holds the Arc, so there are no readers left.
+        unsafe { drop(Box::from_raw(*self.current.get_mut())); }
+    }
+}
+
+/// Wraps a list owned by a single writer. Edit the list through the wrapper,
+/// then call publish() to make the changes visible to readers. The wrapper
+/// derefs to the writer's version of the list, which includes unpublished
+/// edits.
+pub struct SharedList<Item: ListItem, A: NodeAllocator = Global> {
+    inner: Arc<Inner<Item, A>>,
+    /// The writer's copy, if it's been edited since the last publish.
+    write: Option<Box<SkipList<Item, (), A>>>,
+    /// Edits made to the writer's copy since the last publish.
+    log: Vec<Op<Item>>,
+    /// The edits which bring the last retired version up to the published
+    /// one.
+    catch_up: Vec<Op<Item>>,
+}
+
+/// A handle for reading the most recently published version of a
+/// SharedList. Clone it to make a reader for another thread.
+pub struct ListReader<Item: ListItem, A: NodeAllocator = Global> {
+    inner: Arc<Inner<Item, A>>,
+    epoch: Arc<AtomicUsize>,
+}
+
+/// A published version of the list, borrowed from a ListReader. The writer
+/// can publish while this is held, but the version it refers to is kept
+/// around until it's dropped, so don't hold on to it.
+pub struct ReadGuard<'a, Item: ListItem, A: NodeAllocator = Global> {
+    list: &'a SkipList<Item, (), A>,
+    epoch: &'a AtomicUsize,
+}
+
+impl<Item: ListItem + Clone, A: NodeAllocator + Clone> SharedList<Item, A> {
+    /// Wrap a list, publishing its current contents.
+    pub fn new(list: SkipList<Item, (), A>) -> Self {
+        SharedList {
+            inner: Arc::new(Inner {
+                current: AtomicPtr::new(Box::into_raw(Box::new(list))),
+                epochs: Mutex::new(Vec::new()),
+                retired: Mutex::new(Vec::new()),
+            }),
+            write: None,
+            log: Vec::new(),
+            catch_up: Vec::new(),
+        }
+    }
+
+    fn write_list(&mut self) -> &mut SkipList<Item, (), A> {
+        if self.write.is_none() {
+            let reusable = {
+                let mut retired = self.inner.retired.lock().unwrap();
+                let last = match retired.last() {
+                    Some(last) if !last.in_use() => retired.pop(),
+                    _ => None,
+                };
+                retired.retain(Retired::in_use);
+                last
+            };
+
+            let list = match reusable {
+                Some(Retired { mut list, .. }) => {
+                    for op in self.catch_up.drain(..) {
+                        list.apply(&op);
+                    }
+                    list
+                }
+                None => {
+                    self.catch_up.clear();
+                    let current = self.current();
+                    Box::new(SkipList::bulk_load(current.iter().cloned(), current.alloc.clone(), &mut ()))
+                }
+            };
+            self.write = Some(list);
+        }
+        self.write.as_mut().unwrap()
+    }
+
+    /// Apply an edit to the writer's copy of the list.
+    pub fn apply(&mut self, op: Op<Item>) {
+        self.write_list().apply(&op);
+        self.log.push(op);
+    }
+
+    pub fn insert_at_slice(&mut self, userpos: usize, contents: &[Item]) {
+        self.apply(Op::Insert { pos: userpos, items: contents.to_vec() });
+    }
+
+    pub fn del_at(&mut self, userpos: usize, num_items: usize) {
+        self.apply(Op::Delete { pos: userpos, count: num_items });
+    }
+
+    pub fn replace_at_slice(&mut self, start_userpos: usize, removed_items: usize, inserted_content: &[Item]) {
+        self.apply(Op::Replace { pos: start_userpos, removed: removed_items, items: inserted_content.to_vec() });
+    }
+}
+
+impl<Item: ListItem, A: NodeAllocator> SharedList<Item, A> {
+    fn current(&self) -> &SkipList<Item, (), A> {
+        // Only the writer retires the published version.
+        unsafe { &*self.inner.current.load(SeqCst) }
+    }
+
+    /// Make a reader for this list. Readers see the latest published version.
+    pub fn reader(&self) -> ListReader<Item, A> {
+        ListReader::new(self.inner.clone())
+    }
+
+    /// Publish the edits made since the last publish. Readers which start
+    /// reading after this see the new version.
+    ///
+    /// This doesn't wait for readers. Readers still reading the previous
+    /// version carry on with it, and it's freed after they're done.
+    pub fn publish(&mut self) {
+        let list = match self.write.take() {
+            Some(list) => list,
+            None => return,
+        };
+
+        let old = self.inner.current.swap(Box::into_raw(list), SeqCst);
+        // Readers which were mid-read when current changed might be reading
+        // the old version. Note where each of them is up to.
+        let readers = self.inner.epochs.lock().unwrap().iter()
+            .map(|epoch| (epoch.clone(), epoch.load(SeqCst)))
+            .filter(|(_, e)| e % 2 == 1)
+            .collect();
+
+        let mut retired = self.inner.retired.lock().unwrap();
+        retired.retain(Retired::in_use);
+        retired.push(Retired { list: unsafe { Box::from_raw(old) }, readers });
+        self.catch_up = mem::take(&mut self.log);
+    }
+}
+
+impl<Item: ListItem, A: NodeAllocator> Deref for SharedList<Item, A> {
+    type Target = SkipList<Item, (), A>;
+    fn deref(&self) -> &Self::Target {
+        match &self.write {
+            Some(list) => list,
+            None => self.current(),
+        }
+    }
+}
+
+impl<Item: ListItem, A: NodeAllocator> ListReader<Item, A> {
+    fn new(inner: Arc<Inner<Item, A>>) -> Self {
+        let epoch = Arc::new(AtomicUsize::new(0));
+        inner.epochs.lock().unwrap().push(epoch.clone());
+        ListReader { inner, epoch }
+    }
+
+    /// Get the latest published version of the list. This doesn't take any
+    /// locks.
+    pub fn read(&mut self) -> ReadGuard<'_, Item, A> {
+        // Mark ourselves as reading before looking at current. The writer
+        // changes current before checking the epochs, so either it sees that
+        // we're reading, or we see the new version.
+        self.epoch.fetch_add(1, SeqCst);
+        let current = self.inner.current.load(SeqCst);
+        ReadGuard {
+            list: unsafe { &*current },
+            epoch: &self.epoch,
+        }
+    }
+}
+
+impl<Item: ListItem, A: NodeAllocator> Clone for ListReader<Item, A> {
+    fn clone(&self) -> Self {
+        ListReader::new(self.inner.clone())
+    }
+}
+
+impl<Item: ListItem, A: NodeAllocator> Drop for ListReader<Item, A> {
+    fn drop(&mut self) {
+        self.inner.epochs.lock().unwrap().retain(|epoch| !Arc::ptr_eq(epoch, &self.epoch));
+    }
+}
+
+impl<'a, Item: ListItem, A: NodeAllocator> Deref for ReadGuard<'a, Item, A> {
+    type Target = SkipList<Item, (), A>;
+    fn deref(&self) -> &Self::Target { self.list }
+}
+
+impl<'a, Item: ListItem, A: NodeAllocator> Drop for ReadGuard<'a, Item, A> {
+    fn drop(&mut self) {
+        self.epoch.fetch_add(1, SeqCst);
+    }
+}
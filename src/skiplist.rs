@@ -1,18 +1,18 @@
-/// This is an implementation of a general purpose skip list. It was originally
-/// ported from a version of skiplists intended for efficient string handling
-/// found here - https://github.com/josephg/rustrope
+// This is an implementation of a general purpose skip list. It was originally
+// ported from a version of skiplists intended for efficient string handling
+// found here - https://github.com/josephg/rustrope
 
-/// This implementation is not optimized for strings (there's some string
-/// specific features like unicode handling which have been intentionally
-/// removed for simplicity). But it does have another somewhat unusual feature -
-/// users can specify their own size function, and lookups, inserts and deletes
-/// can use their custom length property to specify offsets.
+// This implementation is not optimized for strings (there's some string
+// specific features like unicode handling which have been intentionally
+// removed for simplicity). But it does have another somewhat unusual feature -
+// users can specify their own size function, and lookups, inserts and deletes
+// can use their custom length property to specify offsets.
 
 
 use std::{mem, ptr};
 use std::mem::MaybeUninit;
 use std::ptr::NonNull;
-use std::alloc::{alloc, dealloc, Layout};
+use std::alloc::{handle_alloc_error, Layout};
 use std::cmp::min;
 use std::marker::PhantomData;
 use std::iter;
@@ -22,15 +22,18 @@ use std::fmt;
 use rand::{RngCore, Rng, SeedableRng};
 use rand::rngs::SmallRng;
 
+use allocator::{NodeAllocator, Global};
+use ops::Recorder;
+
 /// The likelyhood a node will have height (n+1) instead of n
 const BIAS: u8 = 100; // out of 256.
 
 /// The number of items in each node. Must fit in a u8 thanks to Node.
 #[cfg(debug_assertions)]
-const NODE_NUM_ITEMS: usize = 10;
+pub(super) const NODE_NUM_ITEMS: usize = 10;
 
 #[cfg(not(debug_assertions))]
-const NODE_NUM_ITEMS: usize = 100;
+pub(super) const NODE_NUM_ITEMS: usize = 100;
 
 /// List operations will move to linear time after NODE_STR_SIZE * 2 ^
 /// MAX_HEIGHT length. (With a smaller constant the higher this is). On the flip
@@ -45,6 +48,10 @@ const MAX_HEIGHT: usize = 10;
 
 const MAX_HEIGHT_U8: u8 = MAX_HEIGHT as u8; // convenience.
 
+/// When a list is compacted, nodes are filled to this many items. Leaving a
+/// bit of slack means subsequent inserts can usually land in an existing node.
+const COMPACT_NODE_ITEMS: usize = NODE_NUM_ITEMS * 3 / 4;
+
 pub struct ItemMarker<Item: ListItem> {
     pub(super) ptr: *mut Node<Item>,
     // _phantom: PhantomData<&'a SkipList<C>>
@@ -55,6 +62,11 @@ impl<Item: ListItem> Clone for ItemMarker<Item> {
     fn clone(&self) -> Self { *self }
 }
 impl<Item: ListItem> Copy for ItemMarker<Item> {}
+impl<Item: ListItem> fmt::Debug for ItemMarker<Item> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ItemMarker").field(&self.ptr).finish()
+    }
+}
 impl<Item: ListItem> PartialEq for ItemMarker<Item> {
     fn eq(&self, other: &Self) -> bool { self.ptr == other.ptr }
 }
@@ -74,6 +86,13 @@ impl<Item: ListItem> Default for ItemMarker<Item> {
     fn default() -> Self { Self::null() }
 }
 
+// Markers are opaque. They can only be dereferenced through the unsafe
+// edit_at_marker family of methods, which require the caller to hold the list
+// (and guarantee the marker is up to date). So its fine to send them between
+// threads along with the notify targets which store them.
+unsafe impl<Item: ListItem> Send for ItemMarker<Item> {}
+unsafe impl<Item: ListItem> Sync for ItemMarker<Item> {}
+
 pub trait ListItem: Sized {
     /// Applications which have custom sizes (or do their own
     /// run-length-encoding) can define their own size function for items. When
@@ -132,6 +151,32 @@ pub trait NotifyTarget<Item: ListItem> {
 
     fn on_set(&mut self, items: &[Item], at_marker: ItemMarker<Item>);
     fn on_delete(&mut self, items: &[Item]);
+
+    // The methods below describe edits in more detail, for change feeds and
+    // the like. They're called alongside on_set / on_delete, and do nothing
+    // by default.
+
+    /// New items were inserted at userpos, into the node at at_marker.
+    fn on_insert(&mut self, _userpos: usize, _items: &[Item], _at_marker: ItemMarker<Item>) {}
+
+    /// The items at userpos are being removed. When they're deleted this
+    /// comes straight after on_delete. It's also called for items overwritten
+    /// by a replace (without on_delete), and then on_insert follows at the
+    /// same userpos with as many replacements.
+    fn on_remove(&mut self, _userpos: usize, _items: &[Item]) {}
+
+    /// Existing items were moved between nodes without changing position in
+    /// the list. from is null if the old node isn't known (eg after compact).
+    fn on_relocate(&mut self, _items: &[Item], _from: ItemMarker<Item>, _to: ItemMarker<Item>) {}
+
+    /// The item at userpos is about to be split at offset to make room for an
+    /// insert. This is followed by the removal of item and the insertion of
+    /// both halves, before the new items are inserted between them.
+    fn on_split(&mut self, _userpos: usize, _item: &Item, _offset: usize) {}
+
+    /// Called when an edit is finished (see Edit::commit), and after bulk
+    /// loads and compaction.
+    fn flush(&mut self) {}
 }
 
 impl<Item: ListItem> NotifyTarget<Item> for () {
@@ -140,6 +185,186 @@ impl<Item: ListItem> NotifyTarget<Item> for () {
     fn on_delete(&mut self, _items: &[Item]) {}
 }
 
+// Notify targets compose. A pair notifies both halves, and an Option only
+// notifies if it's Some.
+impl<Item: ListItem, A: NotifyTarget<Item>, B: NotifyTarget<Item>> NotifyTarget<Item> for (A, B) {
+    const USED: bool = A::USED || B::USED;
+    fn on_set(&mut self, items: &[Item], at_marker: ItemMarker<Item>) {
+        self.0.on_set(items, at_marker);
+        self.1.on_set(items, at_marker);
+    }
+    fn on_delete(&mut self, items: &[Item]) {
+        self.0.on_delete(items);
+        self.1.on_delete(items);
+    }
+    fn on_insert(&mut self, userpos: usize, items: &[Item], at_marker: ItemMarker<Item>) {
+        self.0.on_insert(userpos, items, at_marker);
+        self.1.on_insert(userpos, items, at_marker);
+    }
+    fn on_remove(&mut self, userpos: usize, items: &[Item]) {
+        self.0.on_remove(userpos, items);
+        self.1.on_remove(userpos, items);
+    }
+    fn on_relocate(&mut self, items: &[Item], from: ItemMarker<Item>, to: ItemMarker<Item>) {
+        self.0.on_relocate(items, from, to);
+        self.1.on_relocate(items, from, to);
+    }
+    fn on_split(&mut self, userpos: usize, item: &Item, offset: usize) {
+        self.0.on_split(userpos, item, offset);
+        self.1.on_split(userpos, item, offset);
+    }
+    fn flush(&mut self) {
+        self.0.flush();
+        self.1.flush();
+    }
+}
+
+impl<Item: ListItem, T: NotifyTarget<Item>> NotifyTarget<Item> for Option<T> {
+    const USED: bool = T::USED;
+    fn on_set(&mut self, items: &[Item], at_marker: ItemMarker<Item>) {
+        if let Some(t) = self { t.on_set(items, at_marker); }
+    }
+    fn on_delete(&mut self, items: &[Item]) {
+        if let Some(t) = self { t.on_delete(items); }
+    }
+    fn on_insert(&mut self, userpos: usize, items: &[Item], at_marker: ItemMarker<Item>) {
+        if let Some(t) = self { t.on_insert(userpos, items, at_marker); }
+    }
+    fn on_remove(&mut self, userpos: usize, items: &[Item]) {
+        if let Some(t) = self { t.on_remove(userpos, items); }
+    }
+    fn on_relocate(&mut self, items: &[Item], from: ItemMarker<Item>, to: ItemMarker<Item>) {
+        if let Some(t) = self { t.on_relocate(items, from, to); }
+    }
+    fn on_split(&mut self, userpos: usize, item: &Item, offset: usize) {
+        if let Some(t) = self { t.on_split(userpos, item, offset); }
+    }
+    fn flush(&mut self) {
+        if let Some(t) = self { t.flush(); }
+    }
+}
+
+impl<Item: ListItem, T: NotifyTarget<Item>> NotifyTarget<Item> for &mut T {
+    const USED: bool = T::USED;
+    fn on_set(&mut self, items: &[Item], at_marker: ItemMarker<Item>) {
+        (**self).on_set(items, at_marker);
+    }
+    fn on_delete(&mut self, items: &[Item]) {
+        (**self).on_delete(items);
+    }
+    fn on_insert(&mut self, userpos: usize, items: &[Item], at_marker: ItemMarker<Item>) {
+        (**self).on_insert(userpos, items, at_marker);
+    }
+    fn on_remove(&mut self, userpos: usize, items: &[Item]) {
+        (**self).on_remove(userpos, items);
+    }
+    fn on_relocate(&mut self, items: &[Item], from: ItemMarker<Item>, to: ItemMarker<Item>) {
+        (**self).on_relocate(items, from, to);
+    }
+    fn on_split(&mut self, userpos: usize, item: &Item, offset: usize) {
+        (**self).on_split(userpos, item, offset);
+    }
+    fn flush(&mut self) {
+        (**self).flush();
+    }
+}
+
+/// A notify target made from a pair of closures. See notify_fn.
+pub struct NotifyFn<S, D> {
+    pub on_set: S,
+    pub on_delete: D,
+}
+
+/// Make a notify target from an on_set and an on_delete closure.
+pub fn notify_fn<Item, S, D>(on_set: S, on_delete: D) -> NotifyFn<S, D>
+where Item: ListItem, S: FnMut(&[Item], ItemMarker<Item>), D: FnMut(&[Item]) {
+    NotifyFn { on_set, on_delete }
+}
+
+impl<Item: ListItem, S, D> NotifyTarget<Item> for NotifyFn<S, D>
+where S: FnMut(&[Item], ItemMarker<Item>), D: FnMut(&[Item]) {
+    fn on_set(&mut self, items: &[Item], at_marker: ItemMarker<Item>) {
+        (self.on_set)(items, at_marker);
+    }
+    fn on_delete(&mut self, items: &[Item]) {
+        (self.on_delete)(items);
+    }
+}
+
+/// Helps notify targets which only track positions see through splits. A
+/// split is reported as the removal of the item and the insertion of both
+/// halves, which on its own looks like the item's range was deleted and
+/// replaced with new content.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SplitFilter {
+    remove_pending: bool,
+    insert_pending: usize,
+}
+
+impl SplitFilter {
+    /// Call from on_split, with the usersize of the item being split.
+    pub(crate) fn on_split(&mut self, usersize: usize) {
+        self.remove_pending = true;
+        self.insert_pending = usersize;
+    }
+
+    /// Call from on_remove. Returns true if this is the split item being
+    /// removed.
+    pub(crate) fn take_remove(&mut self) -> bool {
+        mem::replace(&mut self.remove_pending, false)
+    }
+
+    /// Call from on_insert with the usersize of the inserted items. Returns
+    /// true if they're the halves of the split item being put back.
+    pub(crate) fn take_insert(&mut self, usersize: usize) -> bool {
+        if self.insert_pending == 0 { return false; }
+        debug_assert!(!self.remove_pending && usersize <= self.insert_pending);
+        self.insert_pending -= usersize;
+        true
+    }
+}
+
+/// Helps notify targets which only track positions see a replace as a change
+/// in place. A delete reports on_delete before on_remove. A replace only
+/// reports on_remove for the overwritten items, and then inserts the same
+/// number of replacements at the same position, so the pair can be matched
+/// up item by item.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ReplaceFilter {
+    deleting: bool,
+    /// Where the overwritten items were, and each one's usersize.
+    replaced: Option<(usize, Vec<usize>)>,
+}
+
+impl ReplaceFilter {
+    /// Call from on_delete.
+    pub(crate) fn on_delete(&mut self) {
+        self.deleting = true;
+    }
+
+    /// Call from on_remove. Returns true if the items are being deleted.
+    /// Otherwise they're being replaced, and are held until take_replaced.
+    pub(crate) fn take_delete<Item: ListItem>(&mut self, userpos: usize, items: &[Item]) -> bool {
+        if mem::replace(&mut self.deleting, false) { return true; }
+        debug_assert!(self.replaced.is_none());
+        self.replaced = Some((userpos, items.iter().map(|item| item.get_usersize()).collect()));
+        false
+    }
+
+    /// Call from on_insert. If the inserted items replace the items removed
+    /// just before, returns (userpos, old usersize, new usersize) for each
+    /// of them.
+    pub(crate) fn take_replaced<Item: ListItem>(&mut self, userpos: usize, items: &[Item]) -> Option<Vec<(usize, usize, usize)>> {
+        let (mut pos, old_lens) = self.replaced.take()?;
+        debug_assert!(pos == userpos && old_lens.len() == items.len());
+        Some(old_lens.into_iter().zip(items).map(|(old_len, item)| {
+            let new_len = item.get_usersize();
+            pos += new_len;
+            (pos - new_len, old_len, new_len)
+        }).collect())
+    }
+}
+
 /// This represents a single entry in either the nexts pointers list or in an
 /// iterator.
 #[derive(Debug, PartialEq, Eq)]
@@ -204,6 +429,10 @@ pub(super) struct Node<Item: ListItem> {
     /// that we need to be able to figure out at each level of the nexts
     /// pointers which object points to us, and the offset from that element to
     /// the current element. Anyway, for markers to work we need this.
+    ///
+    /// Nodes whose parent is the head store null here instead. The head lives
+    /// inline in the SkipList, so a pointer to it would dangle as soon as the
+    /// list is moved.
     parent: *mut Node<Item>,
 
     // #[repr(align(std::align_of::<SkipEntry>()))]
@@ -218,7 +447,7 @@ pub(super) struct Node<Item: ListItem> {
 // This test will fail if this ever stops being true.
 #[test]
 fn test_align() {
-    struct Item(u8);
+    struct Item(#[allow(dead_code)] u8);
     impl ListItem for Item {}
     #[repr(C)] struct Check([SkipEntry<Item>; 0]);
     assert!(mem::align_of::<Check>() >= mem::align_of::<SkipEntry<Item>>());
@@ -232,8 +461,71 @@ fn random_height<R: RngCore>(rng: &mut R) -> u8 {
     h
 }
 
+/// A cache of freed nodes, bucketed by height. When enabled, nodes removed
+/// from the list are dropped and kept here instead of being returned to the
+/// allocator, and new nodes are taken from here when one with the right height
+/// is available. This saves allocator round trips under heavy edit churn.
+struct NodePool<Item: ListItem> {
+    enabled: bool,
+
+    /// The number of nodes across all the free lists.
+    len: usize,
+
+    /// A singly linked list of free nodes for each height (free[0] holds nodes
+    /// of height 1). Free nodes are linked together through their parent
+    /// pointers. The items in pooled nodes have already been dropped.
+    free: [*mut Node<Item>; MAX_HEIGHT],
+}
+
+impl<Item: ListItem> NodePool<Item> {
+    fn new() -> Self {
+        NodePool {
+            enabled: false,
+            len: 0,
+            free: [ptr::null_mut(); MAX_HEIGHT],
+        }
+    }
+
+    fn take(&mut self, height: u8) -> Option<*mut Node<Item>> {
+        let slot = &mut self.free[height as usize - 1];
+        if slot.is_null() { return None; }
+
+        let node = *slot;
+        unsafe {
+            *slot = (*node).parent;
+            Node::init(node, height);
+        }
+        self.len -= 1;
+        Some(node)
+    }
+
+    /// Add a node to the pool. The node's contents must already be dropped.
+    unsafe fn put(&mut self, node: *mut Node<Item>) {
+        let slot = &mut self.free[(*node).height as usize - 1];
+        (*node).parent = *slot;
+        *slot = node;
+        self.len += 1;
+    }
+
+    /// Return all pooled nodes to the allocator.
+    fn clear<A: NodeAllocator>(&mut self, alloc: &A) {
+        for slot in self.free.iter_mut() {
+            let mut node = *slot;
+            while !node.is_null() {
+                unsafe {
+                    let next = (*node).parent;
+                    Node::dealloc(node, alloc);
+                    node = next;
+                }
+            }
+            *slot = ptr::null_mut();
+        }
+        self.len = 0;
+    }
+}
+
 #[repr(C)]
-pub struct SkipList<Item: ListItem, N: NotifyTarget<Item> = ()> {
+pub struct SkipList<Item: ListItem, N: NotifyTarget<Item> = (), A: NodeAllocator = Global> {
     // TODO: Consider putting the head item on the heap. For the use case here
     // its almost certainly fine either way. The code feels a bit cleaner if its
     // on the heap (and then iterators will be able to outlast a move of the
@@ -257,6 +549,27 @@ pub struct SkipList<Item: ListItem, N: NotifyTarget<Item> = ()> {
     /// ??
     rng: Option<SmallRng>,
 
+    /// Freed nodes kept around for reuse. This is disabled by default.
+    pool: NodePool<Item>,
+
+    /// Bumped by every change to the list. Cached cursors record the version
+    /// they were made at, and History uses it to notice edits it didn't see.
+    pub(super) version: usize,
+
+    /// The cursor left by the most recent edit, the address of the head when
+    /// it was saved, and the list's version at the time. Edits tend to be
+    /// near each other, so SimpleApi searches from here when it can. The
+    /// cursor is discarded if the list has moved (since it points into the
+    /// inline head) or changed since.
+    last_cursor: Option<(Cursor<Item>, *const Node<Item>, usize)>,
+
+    /// Logs every edit while the list is being recorded. Boxed, since it's
+    /// almost always None.
+    pub(super) recorder: Option<Box<Recorder<Item>>>,
+
+    /// The allocator used for all nodes other than the head.
+    pub(super) alloc: A,
+
     /// The first node is inline. The height is 1 more than the max height we've
     /// ever used. The highest next entry points to {null, total usersize}.
     head: Node<Item>,
@@ -274,6 +587,25 @@ pub struct SkipList<Item: ListItem, N: NotifyTarget<Item> = ()> {
     _phantom: PhantomData<N>
 }
 
+// The raw pointers in a SkipList make it !Send and !Sync by default, but they
+// don't share anything with other lists. Every node reachable from the head
+// (and every node in the pool) is exclusively owned by the list, and is freed
+// when the list is dropped. So the list behaves like a Vec<Item>:
+//
+// - Sending a list to another thread sends the items (and allocator) with it.
+//   No other list or thread holds pointers into the nodes. Cursors and Edit
+//   objects borrow the list, so they can't outlive the move. Markers can be
+//   held elsewhere, but dereferencing one needs &mut access to the list.
+// - Through &SkipList you can only read. None of the &self methods mutate
+//   nodes, and there's no interior mutability anywhere in the list. (The RNG
+//   and the node pool are only touched through &mut self.)
+// - The recorder only holds items, and a plain fn pointer to clone them.
+//
+// N is never stored in the list; the bounds on it are just conservative.
+unsafe impl<Item, N, A> Send for SkipList<Item, N, A>
+    where Item: ListItem + Send, N: NotifyTarget<Item> + Send, A: NodeAllocator + Send {}
+unsafe impl<Item, N, A> Sync for SkipList<Item, N, A>
+    where Item: ListItem + Sync, N: NotifyTarget<Item> + Sync, A: NodeAllocator + Sync {}
 
 impl<Item: ListItem> Node<Item> {
     // Do I need to be explicit about the lifetime of the references being tied
@@ -296,34 +628,45 @@ impl<Item: ListItem> Node<Item> {
             mem::align_of::<Node<Item>>()).unwrap()
     }
 
-    fn alloc_with_height(height: u8) -> *mut Node<Item> {
-        assert!(height >= 1 && height <= MAX_HEIGHT_U8);
+    /// Write an empty node with the specified height into memory allocated
+    /// with layout_with_height. Any previous contents are overwritten.
+    unsafe fn init(node: *mut Node<Item>, height: u8) {
+        node.write(Node {
+            items: uninit_items_array(),
+            num_items: 0,
+            height,
+            parent: ptr::null_mut(),
+            nexts: [],
+        });
 
-        unsafe {
-            let node = alloc(Self::layout_with_height(height)) as *mut Node<Item>;
-            node.write(Node {
-                items: uninit_items_array(),
-                num_items: 0,
-                height,
-                parent: ptr::null_mut(),
-                nexts: [],
-            });
+        for next in (*node).nexts_mut() {
+            *next = SkipEntry::new_null();
+        }
+    }
 
-            for next in (*node).nexts_mut() {
-                *next = SkipEntry::new_null();
-            }
+    fn alloc_with_height<A: NodeAllocator>(height: u8, alloc: &A) -> *mut Node<Item> {
+        assert!((1..=MAX_HEIGHT_U8).contains(&height));
 
+        let layout = Self::layout_with_height(height);
+        unsafe {
+            let node = match alloc.allocate(layout) {
+                Some(ptr) => ptr.as_ptr() as *mut Node<Item>,
+                None => handle_alloc_error(layout),
+            };
+            Self::init(node, height);
             node
         }
     }
 
-    fn alloc<R: RngCore>(rng: &mut R) -> *mut Node<Item> {
-        Self::alloc_with_height(random_height(rng))
+    unsafe fn free<A: NodeAllocator>(p: *mut Node<Item>, alloc: &A) {
+        ptr::drop_in_place(p); // We could just implement drop here, but this is cleaner.
+        Self::dealloc(p, alloc);
     }
 
-    unsafe fn free(p: *mut Node<Item>) {
-        ptr::drop_in_place(p); // We could just implement drop here, but this is cleaner.
-        dealloc(p as *mut u8, Self::layout_with_height((*p).height));
+    /// Release the memory of a node which has already been dropped.
+    unsafe fn dealloc<A: NodeAllocator>(p: *mut Node<Item>, alloc: &A) {
+        let layout = Self::layout_with_height((*p).height);
+        alloc.deallocate(NonNull::new_unchecked(p as *mut u8), layout);
     }
 
     fn content_slice(&self) -> &[Item] {
@@ -349,9 +692,9 @@ impl<Item: ListItem> Node<Item> {
         self.first_skip_entry().node
     }
 
-    pub(crate) fn iter(&self, local_index: usize) -> ListItemIter<Item> {
+    pub(crate) fn iter(&self, local_index: usize) -> ListItemIter<'_, Item> {
         ListItemIter {
-            node: Some(&self),
+            node: Some(self),
             index: local_index,
             remaining_items: None
         }
@@ -397,7 +740,6 @@ impl<'a, Item: ListItem> Iterator for NodeIter<'a, Item> {
 ///   the write function is invalid.
 /// - While a cursor is held the SkipList struct should be considered pinned and
 ///   must not be moved or deleted
-#[derive(Copy, Clone)]
 pub(crate) struct Cursor<Item: ListItem> {
     /// The global user position of the cursor in the entire list. This is used
     /// for when the max seen height increases, so we can populate previously
@@ -484,6 +826,15 @@ impl<Item: ListItem> Cursor<Item> {
         for _ in 0..num { self.advance_item(height); }
     }
 
+    /// Adjust the cursor's position after the item before it changes size.
+    /// The cursor stays between the same two items.
+    pub(super) fn resize_prev_item(&mut self, height: usize, by: isize) {
+        for entry in &mut self.entries[0..height] {
+            entry.skip_usersize = entry.skip_usersize.wrapping_add(by as usize);
+        }
+        self.userpos = self.userpos.wrapping_add(by as usize);
+    }
+
     pub(super) fn move_to_item_start(&mut self, height: usize, offset: usize) {
         for entry in &mut self.entries[0..height] {
             entry.skip_usersize -= offset;
@@ -551,6 +902,12 @@ impl<Item: ListItem> Cursor<Item> {
     }
 }
 
+// Derived Clone / Copy would require Item: Copy.
+impl<Item: ListItem> Clone for Cursor<Item> {
+    fn clone(&self) -> Self { *self }
+}
+impl<Item: ListItem> Copy for Cursor<Item> {}
+
 impl<Item: ListItem> PartialEq for Cursor<Item> {
     /// Warning: This returns false if one cursor is at the end of a node, and
     /// the other at the start of the next node. Almost all code in this library
@@ -629,6 +986,46 @@ impl<'a, Item: ListItem, N: NotifyTarget<Item>> Iterator for UninitOwnedIter<'a,
     }
 }
 
+/// Iterator which takes ownership of the items in a chain of nodes which has
+/// been detached from its list. Each node is marked empty once its items have
+/// been moved out, but the nodes themselves aren't freed - that's left to the
+/// owner of the chain, once nothing is reading from it. Any items which
+/// haven't been consumed are dropped along with the iterator.
+struct NodeDrain<Item: ListItem> {
+    node: *mut Node<Item>,
+    index: usize,
+}
+
+impl<Item: ListItem> Iterator for NodeDrain<Item> {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Item> {
+        unsafe {
+            while !self.node.is_null() {
+                let n = &mut *self.node;
+                if self.index < n.num_items as usize {
+                    let item = n.items[self.index].as_ptr().read();
+                    self.index += 1;
+                    return Some(item);
+                }
+
+                // All the items have been moved out. Make sure the node
+                // doesn't drop them again.
+                n.num_items = 0;
+                self.node = n.get_next_ptr();
+                self.index = 0;
+            }
+            None
+        }
+    }
+}
+
+impl<Item: ListItem> Drop for NodeDrain<Item> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
+
 // TODO: Stolen from MaybeUninit::uninit_array. Replace with the real uninit_array when stable.
 #[inline(always)]
 fn uninit_items_array<T>() -> [MaybeUninit<T>; NODE_NUM_ITEMS] {
@@ -648,10 +1045,23 @@ unsafe fn maybeinit_slice_get_ref<T>(slice: &[MaybeUninit<T>]) -> &[T] {
 
 impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
     pub fn new() -> Self {
-        SkipList::<Item, N> {
+        Self::new_in(Global)
+    }
+}
+
+impl<Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> SkipList<Item, N, A> {
+    /// Create a new empty list which allocates its nodes using the specified
+    /// allocator.
+    pub fn new_in(alloc: A) -> Self {
+        SkipList::<Item, N, A> {
             num_items: 0,
             num_usercount: 0,
             rng: None,
+            pool: NodePool::new(),
+            version: 0,
+            last_cursor: None,
+            recorder: None,
+            alloc,
             head: Node {
                 items: uninit_items_array(),
                 num_items: 0,
@@ -682,17 +1092,52 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
     }
 
 
+    /// Keep freed nodes in a per-list pool so they can be reused by later
+    /// inserts, instead of returning them to the allocator. Pooled memory is
+    /// held until shrink_to_fit is called or the list is dropped.
+    pub fn enable_node_pool(&mut self) {
+        self.pool.enabled = true;
+    }
+
+    /// Release any nodes held in the node pool back to the allocator.
+    pub fn shrink_to_fit(&mut self) {
+        self.pool.clear(&self.alloc);
+    }
+
+    /// The number of freed nodes currently held in the node pool.
+    pub fn num_pooled_nodes(&self) -> usize {
+        self.pool.len
+    }
+
+    /// Allocate a new empty node with a random height, reusing a pooled node
+    /// if we can.
+    fn alloc_node(&mut self) -> *mut Node<Item> {
+        let height = random_height(self.get_rng());
+        self.pool.take(height).unwrap_or_else(|| Node::alloc_with_height(height, &self.alloc))
+    }
+
+    /// Drop the contents of a node which has been removed from the list, and
+    /// either stash it in the pool or free it.
+    unsafe fn free_node(&mut self, node: *mut Node<Item>) {
+        if self.pool.enabled {
+            ptr::drop_in_place(node);
+            self.pool.put(node);
+        } else {
+            Node::free(node, &self.alloc);
+        }
+    }
+
     pub fn len_user(&self) -> usize {
         self.num_usercount
     }
 
     pub fn len_items(&self) -> usize {
-        self.num_items as usize
+        self.num_items
     }
 
-    fn node_iter(&self) -> NodeIter<Item> { NodeIter(Some(&self.head)) }
+    fn node_iter(&self) -> NodeIter<'_, Item> { NodeIter(Some(&self.head)) }
     
-    pub fn iter(&self) -> ListItemIter<Item> {
+    pub fn iter(&self) -> ListItemIter<'_, Item> {
         ListItemIter {
             node: Some(&self.head),
             index: 0,
@@ -712,7 +1157,13 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
     }
 
     fn is_head(&self, node: *const Node<Item>) -> bool {
-        node as *const _ == &self.head as *const _
+        ptr::eq(node, &self.head)
+    }
+
+    /// Convert a node pointer into the value stored in a node's parent field.
+    /// See the comment on Node::parent.
+    fn parent_ptr(&self, node: *mut Node<Item>) -> *mut Node<Item> {
+        if self.is_head(node) { ptr::null_mut() } else { node }
     }
 
     #[inline(always)]
@@ -748,7 +1199,7 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
             let mut num_items = 0;
             let mut num_usercount = 0;
 
-            for (_i, n) in self.node_iter().enumerate() {
+            for n in self.node_iter() {
                 // println!("visiting {:?}", n.as_str());
                 if !self.is_head(n) { assert!(n.num_items > 0); }
                 assert!(n.height <= MAX_HEIGHT_U8);
@@ -759,12 +1210,12 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
                 assert_eq!(local_count, n.get_userlen());
 
                 if Self::use_parents() {
-                    let expect_parent = if self.is_head(n) {
-                        ptr::null() // The head's parent is null
-                    } else if n.height == self.head.height {
-                        &self.head as *const _ // Max height nodes point back to head
+                    // The head's parent is null. Max height nodes point back
+                    // to head, which is also stored as null.
+                    let expect_parent = if self.is_head(n) || n.height == self.head.height {
+                        ptr::null()
                     } else {
-                        prev[n.height as usize]
+                        self.parent_ptr(prev[n.height as usize] as *mut _)
                     };
 
                     assert_eq!(n.parent as *const _, expect_parent, "invalid parent");
@@ -791,7 +1242,7 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
                 // Dirty hack. If n has 0-sized elements at the end, the normal
                 // cursor won't be at the end...
                 if Self::use_parents() {
-                    while normal_iter.here_ptr() != n as *const _ as *mut _ {
+                    while !ptr::eq(normal_iter.here_ptr(), n) {
                         normal_iter.advance_node();
                     }
                     normal_iter.local_index = n.num_items as usize;
@@ -834,11 +1285,6 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
     pub(super) fn cursor_at_userpos(&self, target_userpos: usize) -> (Cursor<Item>, usize) {
         assert!(target_userpos <= self.len_user());
 
-        let mut e: *const Node<Item> = &self.head;
-        let mut height = self.height() - 1;
-        
-        let mut offset = target_userpos; // How many more items to skip
-
         // We're populating the head node pointer to simplify the case when the
         // iterator grows. We could put offset into the skip_usersize but it
         // would only be *mostly* correct, not always correct. (Since cursor
@@ -853,13 +1299,22 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
             // _marker: PhantomData,
         };
 
+        let offset = self.descend(&mut cursor, &self.head, self.height() - 1, target_userpos);
+        (cursor, offset)
+    }
+
+    /// Fill in cursor.entries[0..=height] by searching down from node e, which
+    /// must be the right node at the given height. offset is the target
+    /// position relative to the start of e. Returns the offset into the item
+    /// at the cursor, and sets local_index. cursor.userpos isn't touched.
+    fn descend(&self, cursor: &mut Cursor<Item>, mut e: *const Node<Item>, mut height: usize, mut offset: usize) -> usize {
         loop { // while height >= 0
             let en = unsafe { &*e };
             let next = en.nexts()[height];
             let skip = next.skip_usersize;
             if offset > skip {
                 // Go right.
-                debug_assert!(e == &self.head || en.num_items > 0);
+                debug_assert!(ptr::eq(e, &self.head) || en.num_items > 0);
                 offset -= skip;
                 e = next.node;
                 assert!(!e.is_null(), "Internal constraint violation: Reached end prematurely");
@@ -891,7 +1346,88 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
         }
         cursor.local_index = index;
 
-        (cursor, offset)
+        offset
+    }
+
+    /// Move a cursor to the specified userpos, starting from where the cursor
+    /// is now (a finger search). This climbs the cursor's tower until it finds
+    /// a node which spans the target, then searches down from there. That
+    /// takes O(log distance) rather than the O(log n) of a search from the
+    /// head. The cursor ends up exactly where cursor_at_userpos would put it.
+    ///
+    /// Returns the offset into the item at the cursor.
+    pub(super) fn seek_cursor(&self, cursor: &mut Cursor<Item>, target_userpos: usize) -> usize {
+        assert!(target_userpos <= self.len_user());
+
+        let height = self.height();
+        let here = cursor.userpos;
+        let head: *const Node<Item> = &self.head;
+        // Where the node at entries[h] starts.
+        let start_of = |c: &Cursor<Item>, h: usize| c.userpos - c.entries[h].skip_usersize;
+        // Does the node at entries[h] contain the target at this level? (Or
+        // rather, is it where cursor_at_userpos would pass through?)
+        let spans = |c: &Cursor<Item>, h: usize| {
+            let e = c.entries[h];
+            let start = start_of(c, h);
+            (start < target_userpos || ptr::eq(e.node, head))
+                && target_userpos - start <= unsafe { &*e.node }.nexts()[h].skip_usersize
+        };
+
+        let mut h = 0;
+        while !spans(cursor, h) {
+            h += 1;
+            if h == height {
+                // Not even the top of the tower spans the target.
+                let (c, offset) = self.cursor_at_userpos(target_userpos);
+                *cursor = c;
+                return offset;
+            }
+        }
+
+        // Entries above h still span the target. Just fix their offsets.
+        for entry in &mut cursor.entries[h + 1..height] {
+            entry.skip_usersize = entry.skip_usersize.wrapping_add(target_userpos.wrapping_sub(here));
+        }
+        let e = cursor.entries[h].node;
+        let offset = target_userpos - start_of(cursor, h);
+        cursor.userpos = target_userpos;
+        self.descend(cursor, e, h, offset)
+    }
+
+    /// Like cursor_at_userpos, but searches from the cursor left by the last
+    /// edit if it's still valid. Edit saves its cursor after every mutation.
+    /// Any other change to the list bumps the version, which discards it.
+    pub(super) fn cursor_near(&self, target_userpos: usize) -> (Cursor<Item>, usize) {
+        match self.last_cursor {
+            Some((mut cursor, head, version)) if ptr::eq(head, &self.head) && version == self.version => {
+                let offset = self.seek_cursor(&mut cursor, target_userpos);
+                if cfg!(debug_assertions) {
+                    assert!(cursor == self.cursor_at_userpos(target_userpos).0, "Cached cursor is stale");
+                }
+                (cursor, offset)
+            }
+            _ => self.cursor_at_userpos(target_userpos)
+        }
+    }
+
+    pub(super) fn save_cursor(&mut self, cursor: &Cursor<Item>) {
+        self.last_cursor = Some((*cursor, &self.head, self.version));
+    }
+
+    /// Note that the list has changed. This discards the cached cursor.
+    pub(super) fn bump_version(&mut self) {
+        self.version = self.version.wrapping_add(1);
+    }
+
+    /// Change the size of the item before the cursor by the given amount.
+    /// The item itself must already have been changed. The cursor stays
+    /// between the same two items.
+    pub(super) fn resize_prev_item(&mut self, cursor: &mut Cursor<Item>, by: isize) {
+        self.bump_version();
+        let height = self.height();
+        cursor.update_offsets(height, by);
+        self.num_usercount = self.num_usercount.wrapping_add(by as usize);
+        cursor.resize_prev_item(height, by);
     }
 
     /// Create a cursor at the specified node, using the parents infrastructure
@@ -924,9 +1460,11 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
                 h += 1;
             }
 
-            let parent = (*n).parent;
             // Reached the head.
-            if parent.is_null() { break; }
+            if self.is_head(n) { break; }
+            let parent = if (*n).parent.is_null() {
+                &self.head as *const _ as *mut Node<Item>
+            } else { (*n).parent };
 
             // If we're the same height as the parent its fine.
             debug_assert!((*parent).height as usize > h
@@ -951,7 +1489,7 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
 
     /// SAFETY: Self must outlast the marker and not have been moved since the
     /// marker was created. Self should really be Pin<>!
-    pub(super) unsafe fn cursor_at_marker<P>(&mut self, marker: ItemMarker<Item>, predicate: P) -> Option<(Cursor<Item>, usize)>
+    pub(super) unsafe fn cursor_at_marker<P>(&self, marker: ItemMarker<Item>, predicate: P) -> Option<(Cursor<Item>, usize)>
     where P: Fn(&Item) -> Option<usize> {
         // The marker gives us a pointer into a node. Find the item.
         let n = marker.ptr;
@@ -971,16 +1509,48 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
         }
 
         local_index.map(|local_index| {
-            (self.cursor_at_node(n, offset, local_index), item_offset)
+            let mut cursor = self.cursor_at_node(n, offset, local_index);
+            if local_index == 0 && !self.is_head(n) {
+                // cursor_at_node leaves us at the start of the node, but edits
+                // expect the cursor at the end of the previous node. Search
+                // again, then step past any zero sized items before ours.
+                let target = (*n).items[0].as_ptr();
+                let (c, _) = self.cursor_at_userpos(cursor.userpos);
+                cursor = c;
+                while !ptr::eq(cursor.current_item().unwrap(), target) {
+                    cursor.advance_item(self.height());
+                }
+            }
+            (cursor, item_offset)
         })
     }
 
+    /// Like cursor_at_marker, but only find the position of the start of the
+    /// matching item. This skips the work of normalizing the cursor.
+    ///
+    /// SAFETY: As for cursor_at_marker.
+    pub(super) unsafe fn userpos_at_marker<P>(&self, marker: ItemMarker<Item>, mut predicate: P) -> Option<usize>
+    where P: FnMut(&Item) -> bool {
+        let n = marker.ptr;
+        let mut offset: usize = 0;
+        for (i, item) in (*n).content_slice().iter().enumerate() {
+            if predicate(item) {
+                return Some(self.cursor_at_node(n, offset, i).userpos);
+            }
+            offset += item.get_usersize();
+        }
+        None
+    }
+
     // Internal fn to create a new node at the specified iterator filled with
     // the specified content. The passed cursor should point at the end of the
     // previous node. It will be updated to point to the end of the newly
     // inserted content.
     // unsafe fn insert_node_at(&mut self, cursor: &mut Cursor<Item>, contents: &[C::Item], new_userlen: usize, move_cursor: bool) {
-    unsafe fn insert_node_at<I>(&mut self, cursor: &mut Cursor<Item>, contents: &mut I, num_items: usize, move_cursor: bool, notify: &mut N)
+    //
+    // moved_from is the node the contents were taken from if they're existing
+    // items being moved, or null if they're new.
+    unsafe fn insert_node_at<I>(&mut self, cursor: &mut Cursor<Item>, contents: &mut I, num_items: usize, move_cursor: bool, moved_from: *mut Node<Item>, notify: &mut N)
             where I: Iterator<Item=Item> {
 
         // println!("Insert_node_at {} len {}", contents.len(), self.num_bytes);
@@ -988,18 +1558,19 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
         assert!(num_items <= NODE_NUM_ITEMS);
         debug_assert!(contents.size_hint().0 >= num_items);
 
-        let new_node_ptr = Node::alloc(self.get_rng());
+        let new_node_ptr = self.alloc_node();
         let new_node = &mut *new_node_ptr;
         new_node.num_items = num_items as u8;
 
         for (slot, item) in new_node.items[..num_items].iter_mut().zip(contents) {
-            (slot.as_mut_ptr() as *mut Item).write(item); // Write makes sure we don't drop the old value.
+            ptr::write(slot.as_mut_ptr(), item); // Write makes sure we don't drop the old value.
         }
 
         let new_userlen = Item::userlen_of_slice(new_node.content_slice());
 
         let new_height = new_node.height;
         let new_height_usize = new_height as usize;
+        let start_userpos = cursor.userpos;
 
         let mut head_height = self.height();
         while head_height < new_height_usize {
@@ -1018,8 +1589,8 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
         }
 
         new_node.parent = if new_height_usize == MAX_HEIGHT {
-            &self.head as *const _ as *mut _
-        } else { cursor.entries[new_height_usize].node };
+            ptr::null_mut()
+        } else { self.parent_ptr(cursor.entries[new_height_usize].node) };
 
         for i in 0..new_height_usize {
             let prev_skip = &mut (*cursor.entries[i].node).nexts_mut()[i];
@@ -1074,15 +1645,19 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
             cursor.local_index = num_items;
         }
 
-        notify.on_set(new_node.content_slice(), ItemMarker {
-            ptr: new_node_ptr,
-            // _phantom: PhantomData
-        });
+        let marker = ItemMarker { ptr: new_node_ptr };
+        notify.on_set(new_node.content_slice(), marker);
+        if moved_from.is_null() {
+            notify.on_insert(start_userpos, new_node.content_slice(), marker);
+        } else {
+            notify.on_relocate(new_node.content_slice(), ItemMarker { ptr: moved_from }, marker);
+        }
     }
 
     // unsafe fn insert_at_iter(&mut self, cursor: &mut Cursor<C>, contents: &[C::Item]) {
     pub(super) unsafe fn insert_at_iter<I>(&mut self, cursor: &mut Cursor<Item>, contents: &mut I, notify: &mut N)
             where I: ExactSizeIterator<Item=Item> {
+        self.bump_version();
         // iter specifies where to insert.
 
         let mut e = cursor.here_ptr();
@@ -1154,13 +1729,13 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
             for entry in cursor.entries[0..self.height()].iter_mut() {
                 entry.skip_usersize += num_inserted_usercount;
             }
+            let start_userpos = cursor.userpos;
             cursor.userpos += num_inserted_usercount;
             cursor.local_index += num_inserted_items;
 
-            notify.on_set(dest_content_slice, ItemMarker {
-                ptr: e,
-                // _phantom: PhantomData
-            });
+            let marker = ItemMarker { ptr: e };
+            notify.on_set(dest_content_slice, marker);
+            notify.on_insert(start_userpos, dest_content_slice, marker);
         } else {
             // There isn't room. We'll need to add at least one new node to the
             // list. We could be a bit more careful here and copy as much as
@@ -1179,7 +1754,7 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
                 // Note that if we wanted to, it would also be correct (and
                 // slightly more space efficient) to pack some of the new
                 // string's characters into this node after trimming it.
-                let end_items = &(*e).items[item_idx..e_num_items];
+                let end_items = &(&(*e).items)[item_idx..e_num_items];
                 (*e).num_items = item_idx as u8;
                 let end_usercount = (*e).get_userlen() - cursor.entries[0].skip_usersize;
 
@@ -1202,7 +1777,7 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
             let mut items_remaining = num_inserted_items;
             while items_remaining > 0 {
                 let insert_here = usize::min(items_remaining, NODE_NUM_ITEMS);
-                self.insert_node_at(cursor, contents, insert_here, true, notify);
+                self.insert_node_at(cursor, contents, insert_here, true, ptr::null_mut(), notify);
                 items_remaining -= insert_here;
             }
 
@@ -1212,7 +1787,7 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
                 // Passing false to indicate we don't want the cursor updated
                 // after this - it should remain at the end of the newly
                 // inserted content, which is *before* this end bit.
-                self.insert_node_at(cursor, &mut UninitOwnedIter::<Item, N>::from_slice(end_items), end_items.len(), false, notify);
+                self.insert_node_at(cursor, &mut UninitOwnedIter::<Item, N>::from_slice(end_items), end_items.len(), false, e, notify);
             }
         }
     }
@@ -1228,8 +1803,13 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
     /// If the deleted content occurs at the start of a node, the cursor passed
     /// here must point to the end of the previous node, not the start of the
     /// current node.
-    pub(super) unsafe fn del_at_iter(&mut self, cursor: &Cursor<Item>, mut num_deleted_items: usize, notify: &mut N) {
+    ///
+    /// Under-full nodes around the deleted range are merged with their
+    /// neighbours, so the cursor may be moved into the previous node. It will
+    /// still point to the same position in the list.
+    pub(super) unsafe fn del_at_iter(&mut self, cursor: &mut Cursor<Item>, mut num_deleted_items: usize, notify: &mut N) {
         if num_deleted_items == 0 { return; }
+        self.bump_version();
 
         let mut item_idx = cursor.local_index;
         let mut e = cursor.here_ptr();
@@ -1254,14 +1834,18 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
             let height = (*e).height as usize;
             let removed_userlen;
 
-            if removed_here < e_num_items || e as *const _ == &self.head as *const _ {
+            if removed_here < e_num_items || self.is_head(e) {
                 // Just trim the node down.
                 let trailing_items = e_num_items - item_idx - removed_here;
                 
                 let c = &mut (*e).items;
 
                 if N::USED {
-                    notify.on_delete(maybeinit_slice_get_ref(&c[item_idx..item_idx + removed_here]));
+                    let removed = maybeinit_slice_get_ref(&c[item_idx..item_idx + removed_here]);
+                    notify.on_delete(removed);
+                    // Earlier chunks are already gone, so every chunk starts
+                    // at the cursor.
+                    notify.on_remove(cursor.userpos, removed);
                 }
 
                 if mem::needs_drop::<Item>() {
@@ -1292,6 +1876,7 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
 
                 if N::USED {
                     notify.on_delete((*e).content_slice());
+                    notify.on_remove(cursor.userpos, (*e).content_slice());
                 }
 
                 removed_userlen = (*e).get_userlen();
@@ -1299,53 +1884,12 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
 
                 // println!("removing {:?} contents {:?} height {}", e, (*e).content_slice(), height);
 
-                for i in 0..height {
-                    let s = &mut (*cursor.entries[i].node).nexts_mut()[i];
-                    s.node = (*e).nexts_mut()[i].node;
-                    s.skip_usersize += (*e).nexts()[i].skip_usersize - removed_userlen;
-                }
+                self.unlink_node(cursor, e, removed_userlen);
 
                 self.num_items -= (*e).num_items as usize;
                 self.num_usercount -= removed_userlen;
 
-                // Update parents.
-                if Self::use_parents() && height > 1 {
-                    let mut n = e;
-                    // let new_parent = cursor.entries[height - 1].node;
-
-                    // If you imagine this node as a big building, we need to
-                    // update the parent of all the nodes we cast a shadow over.
-                    // So, if our height is 3 and the next nodes have heights 1
-                    // and 2, they both need new parents.
-                    let mut parent_height = 1;
-                    let cursor_node = cursor.here_ptr();
-                    let cursor_node_height = (*cursor_node).height as usize;
-                    let mut new_parent = if height >= cursor_node_height {
-                        cursor.entries[parent_height].node
-                    } else {
-                        cursor_node
-                    };
-
-                    loop {
-                        n = (*n).nexts_mut()[parent_height - 1].node;
-                        if n.is_null() || (*n).height >= height as u8 { break; }
-                        let n_height = (*n).height as usize;
-                        
-                        assert_eq!((*n).parent, e);
-                        assert!(n_height >= parent_height - 1);
-
-                        if n_height > parent_height {
-                            parent_height = n_height;
-                            if n_height >= cursor_node_height {
-                                new_parent = cursor.entries[parent_height].node
-                            }
-                        }
-                        
-                        (*n).parent = new_parent;
-                    }
-                }
-
-                Node::free(e);
+                self.free_node(e);
                 e = next;
             }
 
@@ -1358,12 +1902,137 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
 
             // if cfg!(debug_assertions) { self.check(); }
         }
+
+        // The delete may have left the node at the cursor and its neighbours
+        // mostly empty. Coalesce them so heavy deletes don't leave a long
+        // chain of tiny nodes behind.
+        self.merge_next_node(cursor, notify);
+        self.merge_into_prev_node(cursor, notify);
+    }
+
+    /// Remove node e from the skip list's nexts pointers and update the
+    /// parents of any nodes in its shadow. The node itself is not freed and
+    /// its items are left alone.
+    ///
+    /// The cursor must point to the end of the node directly preceding e.
+    /// removed_userlen is the size which the previous nodes' skip entries lose
+    /// along with e (this is 0 if e's items were moved elsewhere).
+    unsafe fn unlink_node(&mut self, cursor: &Cursor<Item>, e: *mut Node<Item>, removed_userlen: usize) {
+        let height = (*e).height as usize;
+
+        for i in 0..height {
+            let s = &mut (*cursor.entries[i].node).nexts_mut()[i];
+            s.node = (*e).nexts()[i].node;
+            s.skip_usersize += (*e).nexts()[i].skip_usersize - removed_userlen;
+        }
+
+        // Update parents.
+        if Self::use_parents() && height > 1 {
+            // If you imagine this node as a big building, we need to update the
+            // parent of all the nodes we cast a shadow over. So, if our height
+            // is 3 and the next nodes have heights 1 and 2, they both need new
+            // parents. The new parent of each node is the closest taller node
+            // before e, which is exactly what the cursor entries point to.
+            let mut n = e;
+            let mut skip_height = 0;
+
+            loop {
+                n = (*n).nexts()[skip_height].node;
+                if n.is_null() || (*n).height as usize >= height { break; }
+                let n_height = (*n).height as usize;
+
+                debug_assert_eq!((*n).parent, e);
+                (*n).parent = self.parent_ptr(cursor.entries[n_height].node);
+                skip_height = usize::max(skip_height, n_height - 1);
+            }
+        }
+    }
+
+    /// If the node at the cursor and the node after it both fit inside a single
+    /// node, move the successor's items into the cursor's node and free the
+    /// successor. The cursor stays valid. Moved items are announced via
+    /// on_set.
+    unsafe fn merge_next_node(&mut self, cursor: &Cursor<Item>, notify: &mut N) {
+        let e = cursor.here_ptr();
+        let next = (*e).get_next_ptr();
+        if next.is_null() { return; }
+
+        let e_num_items = (*e).num_items as usize;
+        let next_num_items = (*next).num_items as usize;
+        if e_num_items + next_num_items > NODE_NUM_ITEMS { return; }
+
+        // Move the items across. They keep their position in the list, so
+        // none of the skip sizes before or after the pair need to change.
+        ptr::copy_nonoverlapping(
+            &(*next).items[0],
+            &mut (&mut (*e).items)[e_num_items],
+            next_num_items);
+        (*e).num_items += next_num_items as u8;
+        (*next).num_items = 0;
+
+        self.unlink_node(cursor, next, 0);
+        self.free_node(next);
+
+        let moved = &(*e).content_slice()[e_num_items..];
+        notify.on_set(moved, ItemMarker { ptr: e });
+        notify.on_relocate(moved, ItemMarker { ptr: next }, ItemMarker { ptr: e });
+    }
+
+    /// If the node at the cursor is under-full and fits inside the node before
+    /// it, move its items to the end of the previous node and free it. The
+    /// cursor is moved along with the items. Moved items are announced via
+    /// on_set.
+    unsafe fn merge_into_prev_node(&mut self, cursor: &mut Cursor<Item>, notify: &mut N) {
+        let e = cursor.here_ptr();
+        let e_num_items = (*e).num_items as usize;
+        // Finding the previous node needs another walk from the head, so only
+        // bother when this node is less than half full.
+        if self.is_head(e) || e_num_items * 2 >= NODE_NUM_ITEMS { return; }
+
+        // Find a cursor pointing to the end of the node before e. Looking up
+        // the start of e will land us there, or somewhere earlier if there's
+        // zero sized content in the way.
+        let offset = cursor.entries[0].skip_usersize;
+        let (mut prev_cursor, _) = self.cursor_at_userpos(cursor.userpos - offset);
+        while (*prev_cursor.here_ptr()).get_next_ptr() != e {
+            prev_cursor.advance_node();
+        }
+        let prev = prev_cursor.here_ptr();
+        let prev_num_items = (*prev).num_items as usize;
+        prev_cursor.local_index = prev_num_items;
+
+        if prev_num_items + e_num_items > NODE_NUM_ITEMS { return; }
+
+        ptr::copy_nonoverlapping(
+            &(*e).items[0],
+            &mut (&mut (*prev).items)[prev_num_items],
+            e_num_items);
+        (*prev).num_items += e_num_items as u8;
+        (*e).num_items = 0;
+
+        self.unlink_node(&prev_cursor, e, 0);
+        self.free_node(e);
+
+        // The cursor now points into prev, the same distance past the end of
+        // prev's old content as it used to be into e.
+        let Cursor { userpos, local_index, .. } = *cursor;
+        *cursor = prev_cursor;
+        cursor.userpos = userpos;
+        for entry in &mut cursor.entries[0..self.height()] {
+            entry.skip_usersize += offset;
+        }
+        cursor.local_index = prev_num_items + local_index;
+
+        let moved = &(*prev).content_slice()[prev_num_items..];
+        notify.on_set(moved, ItemMarker { ptr: prev });
+        notify.on_relocate(moved, ItemMarker { ptr: e }, ItemMarker { ptr: prev });
     }
 
 
     pub(super) unsafe fn replace_at_iter<I>(&mut self, cursor: &mut Cursor<Item>, mut removed_items: usize, inserted_content: &mut I, notify: &mut N)
             where I: ExactSizeIterator<Item=Item> {
         if removed_items == 0 && inserted_content.len() == 0 { return; }
+        self.bump_version();
 
         // Replace as many items from removed_items as we can with inserted_content.
         let mut replaced_items = min(removed_items, inserted_content.len());
@@ -1383,8 +2052,10 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
             let e_num_items = (*e).num_items as usize;
             let replaced_items_here = min(replaced_items, e_num_items - index);
 
-            let dest = &mut (*e).items[index..index + replaced_items_here];
+            let dest = &mut (&mut (*e).items)[index..index + replaced_items_here];
             let old_usersize = Item::userlen_of_slice(maybeinit_slice_get_ref(dest));
+            let start_userpos = cursor.userpos;
+            notify.on_remove(start_userpos, maybeinit_slice_get_ref(dest));
 
             // Replace the items themselves. Everything else is commentary.
             // Would prefer to use zip() but it wants ownership of inserted_content :/
@@ -1412,10 +2083,9 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
             }
             cursor.userpos += new_usersize;
 
-            notify.on_set(dest, ItemMarker {
-                ptr: e,
-                // _phantom: PhantomData,
-            });
+            let marker = ItemMarker { ptr: e };
+            notify.on_set(dest, marker);
+            notify.on_insert(start_userpos, dest, marker);
         }
 
         // Ok now one of two things must be true. Either we've run out of
@@ -1429,6 +2099,142 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
         }
     }
 
+    /// Fill an empty list from an iterator, in a single pass. Each node is
+    /// filled with node_size() items (or however many are left) and linked
+    /// into every level as we go, so there's no need to walk the list with a
+    /// cursor for each node.
+    ///
+    /// If fill_head is false the head node is left empty and all items are
+    /// placed in allocated nodes.
+    unsafe fn fill_from_iter<I, S>(&mut self, contents: I, mut node_size: S, fill_head: bool, relocating: bool, notify: &mut N)
+            where I: Iterator<Item=Item>, S: FnMut() -> usize {
+        debug_assert_eq!(self.num_items, 0);
+        debug_assert_eq!(self.height(), 1);
+
+        let mut contents = contents.peekable();
+
+        // The last node we've seen at each height, and the userpos of the start
+        // of that node. This is the mirror image of a cursor. Null stands for
+        // the head: the list is reborrowed to allocate each node, so pointers
+        // to the head are taken fresh whenever they're used.
+        let mut prev = [SkipEntry { node: ptr::null_mut(), skip_usersize: 0 }; MAX_HEIGHT];
+        let mut node = ptr::null_mut();
+        if !fill_head {
+            if contents.peek().is_none() { return; }
+            node = self.alloc_node();
+        }
+
+        loop {
+            let n: *mut Node<Item> = if node.is_null() { &mut self.head } else { node };
+            let items_per_node = node_size();
+            assert!(items_per_node <= NODE_NUM_ITEMS);
+            let mut num_items = 0;
+            for (slot, item) in (&mut (*n).items)[..items_per_node].iter_mut().zip(contents.by_ref().take(items_per_node)) {
+                ptr::write(slot.as_mut_ptr(), item);
+                num_items += 1;
+            }
+            (*n).num_items = num_items as u8;
+            let userlen = Item::userlen_of_slice((*n).content_slice());
+
+            if !node.is_null() {
+                let height = (*n).height as usize;
+                while self.head.height < (*n).height { self.head.height += 1; }
+
+                for (i, p) in prev[..height].iter_mut().enumerate() {
+                    let p_node: *mut Node<Item> = if p.node.is_null() { &mut self.head } else { p.node };
+                    (*p_node).nexts_mut()[i] = SkipEntry {
+                        node,
+                        skip_usersize: self.num_usercount - p.skip_usersize
+                    };
+                    *p = SkipEntry { node, skip_usersize: self.num_usercount };
+                }
+
+                // Null is also how nodes refer to the head as a parent.
+                (*n).parent = if height == MAX_HEIGHT {
+                    ptr::null_mut()
+                } else { prev[height].node };
+            }
+
+            self.num_items += num_items;
+            self.num_usercount += userlen;
+
+            if num_items > 0 {
+                let marker = ItemMarker { ptr: n };
+                notify.on_set((*n).content_slice(), marker);
+                if relocating {
+                    notify.on_relocate((*n).content_slice(), ItemMarker::null(), marker);
+                } else {
+                    notify.on_insert(self.num_usercount - userlen, (*n).content_slice(), marker);
+                }
+            }
+
+            if contents.peek().is_none() { break; }
+            node = self.alloc_node();
+        }
+
+        // Terminate each level of the list.
+        for (i, p) in prev[..self.height()].iter().enumerate() {
+            let p_node: *mut Node<Item> = if p.node.is_null() { &mut self.head } else { p.node };
+            (*p_node).nexts_mut()[i] = SkipEntry {
+                node: ptr::null_mut(),
+                skip_usersize: self.num_usercount - p.skip_usersize
+            };
+        }
+    }
+
+    /// Repack all the items in the list into freshly allocated nodes filled to
+    /// COMPACT_NODE_ITEMS, and regenerate all the tower heights. This runs in
+    /// O(n). Every item is re-announced via on_set.
+    pub(super) fn repack(&mut self, notify: &mut N) {
+        self.bump_version();
+        unsafe {
+            // Detach everything from the head, then refill the list from the
+            // detached items.
+            let head_items: Vec<Item> = UninitOwnedIter::<Item, N>::from_slice(&self.head.items[..self.head.num_items as usize]).collect();
+            self.head.num_items = 0;
+
+            let old_nodes = self.head.get_next_ptr();
+            for entry in self.heads_mut() {
+                *entry = SkipEntry::new_null();
+            }
+            self.head.height = 1;
+            self.num_items = 0;
+            self.num_usercount = 0;
+
+            let drain = NodeDrain { node: old_nodes, index: 0 };
+            self.fill_from_iter(head_items.into_iter().chain(drain), || COMPACT_NODE_ITEMS, true, true, notify);
+
+            // The old nodes have all been emptied by now. They're freed
+            // afterwards rather than during the refill, because the refill
+            // needs the list (and its allocator) borrowed mutably.
+            let mut node = old_nodes;
+            while !node.is_null() {
+                let next = (*node).get_next_ptr();
+                self.free_node(node);
+                node = next;
+            }
+        }
+        notify.flush();
+    }
+
+    /// Create a new list from an iterator using a single left-to-right pass.
+    /// Nodes are filled completely, and on_set is called once per node.
+    pub(super) fn bulk_load<I>(contents: I, alloc: A, notify: &mut N) -> Self
+            where I: Iterator<Item=Item> {
+        let mut list = Self::new_in(alloc);
+        // The list is about to be moved to the caller, which would invalidate
+        // any markers pointing to the head. So if anyone is listening, leave
+        // the head empty.
+        unsafe { list.fill_from_iter(contents, || NODE_NUM_ITEMS, !N::USED, false, notify); }
+        notify.flush();
+        list
+    }
+
+    /// The number of items in each node, starting with the head.
+    pub(super) fn node_sizes(&self) -> impl Iterator<Item=usize> + '_ {
+        self.node_iter().map(|node| node.num_items as usize)
+    }
+
     pub(super) unsafe fn replace_item(&mut self, cursor: &mut Cursor<Item>, new_item: Item, notify: &mut N) {
         // This could easily be optimized.
         self.replace_at_iter(cursor, 1, &mut iter::once(new_item), notify);
@@ -1469,9 +2275,26 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
     }
 }
 
+impl<Item: ListItem, A: NodeAllocator> SkipList<Item, (), A> {
+    /// Like bulk_load, but with a given number of items in each node, starting
+    /// with the head. The sizes must be at most NODE_NUM_ITEMS, and only the
+    /// head may be empty. Nodes past the end of node_sizes are filled.
+    ///
+    /// The head gets items too, so this is only for lists without a notify
+    /// target.
+    pub(super) fn bulk_load_with_layout<I>(contents: I, node_sizes: &[usize], alloc: A) -> Self
+            where I: Iterator<Item=Item> {
+        debug_assert!(node_sizes.iter().skip(1).all(|&size| size > 0));
+        let mut list = Self::new_in(alloc);
+        let mut sizes = node_sizes.iter().copied();
+        unsafe { list.fill_from_iter(contents, || sizes.next().unwrap_or(NODE_NUM_ITEMS), true, false, &mut ()); }
+        list
+    }
+}
 
 
-impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
+
+impl<Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> SkipList<Item, N, A> {
     pub fn eq_list<Rhs>(&self, other: &[Rhs]) -> bool where Item: PartialEq<Rhs> {
         let mut pos = 0;
         let other_len = other.len();
@@ -1490,16 +2313,17 @@ impl<Item: ListItem, N: NotifyTarget<Item>> SkipList<Item, N> {
     }
 }
 
-impl<Item: ListItem, N: NotifyTarget<Item>> Drop for SkipList<Item, N> {
+impl<Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> Drop for SkipList<Item, N, A> {
     fn drop(&mut self) {
         let mut node = self.head.first_skip_entry().node;
         unsafe {
             while !node.is_null() {
                 let next = (*node).first_skip_entry().node;
-                Node::free(node);
+                Node::free(node, &self.alloc);
                 node = next;
             }
         }
+        self.pool.clear(&self.alloc);
     }
 }
 
@@ -1511,18 +2335,17 @@ impl<I, Item: ListItem> From<I> for SkipList<Item> where I: ExactSizeIterator<It
     }
 }
 
-// Needs me to relax the ExactSizeIterator constraint on insert.
-// impl<Item: ListItem> iter::FromIterator<Item> for SkipList<Item> {
-//     fn from_iter<T: IntoIterator<Item = Item>>(iter: T) -> Self {
-//         SkipList::new_from_iter(iter)
-//     }
-// }
+impl<Item: ListItem> iter::FromIterator<Item> for SkipList<Item> {
+    fn from_iter<T: IntoIterator<Item = Item>>(iter: T) -> Self {
+        SkipList::bulk_load(iter.into_iter(), Global, &mut ())
+    }
+}
 
-impl<Item: ListItem, N: NotifyTarget<Item>> Into<Vec<Item>> for &SkipList<Item, N> where Item: Copy {
-    fn into(self) -> Vec<Item> {
-        let mut content: Vec<Item> = Vec::with_capacity(self.num_items);
+impl<Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> From<&SkipList<Item, N, A>> for Vec<Item> where Item: Copy {
+    fn from(list: &SkipList<Item, N, A>) -> Vec<Item> {
+        let mut content: Vec<Item> = Vec::with_capacity(list.num_items);
 
-        for node in self.node_iter() {
+        for node in list.node_iter() {
             content.extend(node.content_slice().iter());
         }
 
@@ -1530,15 +2353,15 @@ impl<Item: ListItem, N: NotifyTarget<Item>> Into<Vec<Item>> for &SkipList<Item,
     }
 }
 
-impl<Item: ListItem, N: NotifyTarget<Item>> fmt::Debug for SkipList<Item, N> where Item: fmt::Debug {
+impl<Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> fmt::Debug for SkipList<Item, N, A> where Item: fmt::Debug {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_list().entries(self.iter()).finish()
     }
 }
 
-impl<Item: ListItem, N: NotifyTarget<Item>> Default for SkipList<Item, N> {
+impl<Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator + Default> Default for SkipList<Item, N, A> {
     fn default() -> Self {
-        SkipList::new()
+        SkipList::new_in(A::default())
     }
 }
 
@@ -1549,13 +2372,26 @@ pub struct ListItemIter<'a, Item: ListItem> {
     remaining_items: Option<usize> // For size_hint, if known.
 }
 
+// An iterator is just a shared borrow of the list's items, like slice::Iter.
+unsafe impl<'a, Item: ListItem + Sync> Send for ListItemIter<'a, Item> {}
+unsafe impl<'a, Item: ListItem + Sync> Sync for ListItemIter<'a, Item> {}
+
 impl<'a, Item: ListItem> Iterator for ListItemIter<'a, Item> {
     type Item = &'a Item;
 
     fn next(&mut self) -> Option<Self::Item> {
+        // Skip past empty nodes. The head is empty in an empty list, and
+        // lists built in bulk may leave it empty too.
+        while let Some(node) = self.node {
+            if self.index < node.num_items as usize { break; }
+            self.index = 0;
+            self.node = unsafe { node.get_next_ptr().as_ref() };
+        }
+
         if let Some(node) = self.node {
             let current = &node.items[self.index];
             self.index += 1;
+            if let Some(r) = self.remaining_items.as_mut() { *r -= 1; }
             if self.index == node.num_items as usize {
                 self.index = 0;
                 self.node = unsafe { node.get_next_ptr().as_ref() };
@@ -0,0 +1,80 @@
+// All-or-nothing editing. Edits made inside a transaction are recorded in a
+// History as a single group, and undone if the transaction fails.
+
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+
+use {ListItem, NotifyTarget, SkipList, Op, History};
+use allocator::NodeAllocator;
+
+/// The editing handle passed to the closure in SkipList::transaction. It
+/// derefs to the list for reading.
+pub struct Transaction<'a, Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> {
+    list: &'a mut SkipList<Item, N, A>,
+    notify: &'a mut N,
+    history: History<Item>,
+}
+
+impl<'a, Item: ListItem + Clone, N: NotifyTarget<Item>, A: NodeAllocator> Transaction<'a, Item, N, A> {
+    pub fn apply(&mut self, op: Op<Item>) {
+        self.history.apply_n(self.list, self.notify, op);
+    }
+
+    pub fn insert_at_slice(&mut self, userpos: usize, contents: &[Item]) {
+        self.apply(Op::Insert { pos: userpos, items: contents.to_vec() });
+    }
+
+    pub fn del_at(&mut self, userpos: usize, num_items: usize) {
+        self.apply(Op::Delete { pos: userpos, count: num_items });
+    }
+
+    pub fn replace_at_slice(&mut self, start_userpos: usize, removed_items: usize, inserted_content: &[Item]) {
+        self.apply(Op::Replace { pos: start_userpos, removed: removed_items, items: inserted_content.to_vec() });
+    }
+
+    fn rollback(&mut self) {
+        self.history.end_group();
+        self.history.undo_n(self.list, self.notify);
+    }
+}
+
+impl<'a, Item: ListItem, N: NotifyTarget<Item>, A: NodeAllocator> Deref for Transaction<'a, Item, N, A> {
+    type Target = SkipList<Item, N, A>;
+    fn deref(&self) -> &Self::Target { self.list }
+}
+
+impl<Item: ListItem + Clone, N: NotifyTarget<Item>, A: NodeAllocator> SkipList<Item, N, A> {
+    /// Run f, which edits the list through a Transaction. If f returns Err,
+    /// every edit it made is reverted before the error is returned.
+    ///
+    /// If f panics, its edits are reverted too, and then the panic carries on
+    /// unwinding. Rollback only undoes edits which finished, so a panic from
+    /// inside the list's own editing code can still leave that edit half
+    /// done.
+    ///
+    /// The notify target sees each edit as it happens, followed by
+    /// compensating notifications for the reverted edits.
+    pub fn transaction_n<T, E, F>(&mut self, notify: &mut N, f: F) -> Result<T, E>
+            where F: FnOnce(&mut Transaction<'_, Item, N, A>) -> Result<T, E> {
+        let mut history = History::new();
+        history.begin_group();
+        let mut tx = Transaction { list: self, notify, history };
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&mut tx))) {
+            Ok(result) => {
+                if result.is_err() { tx.rollback(); }
+                result
+            },
+            Err(payload) => {
+                tx.rollback();
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+}
+
+impl<Item: ListItem + Clone, A: NodeAllocator> SkipList<Item, (), A> {
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+            where F: FnOnce(&mut Transaction<'_, Item, (), A>) -> Result<T, E> {
+        self.transaction_n(&mut (), f)
+    }
+}
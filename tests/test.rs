@@ -15,7 +15,14 @@ mod test {
     extern crate testdrop;
     use self::testdrop::{Item as TDItem, TestDrop};
 
+    #[cfg(feature = "serde")]
+    extern crate serde_json;
+
     use std::iter;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::alloc::Layout;
+    use std::ptr::NonNull;
 
     fn into_iter<'a, Item, T>(slice: &'a [T]) -> impl 'a + ExactSizeIterator<Item=Item>
     where Item: From<T>, T: Copy {
@@ -47,13 +54,13 @@ mod test {
     }
 
 
-    fn check<'a, Item: ListItem>(list: &SkipList<Item>, expected: &'a [u8])
-    where Item: Debug + Copy + PartialEq + From<u8> {
+    fn check<Item>(list: &SkipList<Item>, expected: &[u8])
+    where Item: ListItem + Debug + Copy + PartialEq + From<u8> {
         check2(list, expected)
     }
 
-    fn check2<'a, Item: ListItem, T>(list: &SkipList<Item>, expected: &'a [T])
-        where Item: Debug + Copy + PartialEq + From<T>, T: Copy {
+    fn check2<Item, T>(list: &SkipList<Item>, expected: &[T])
+        where Item: ListItem + Debug + Copy + PartialEq + From<T>, T: Copy {
         // This is super gross.
         let copy: Vec<Item> = into_iter(expected).collect();
         let expected = copy.as_slice();
@@ -236,7 +243,7 @@ mod test {
         struct N {
             count: u32,
             last: ItemMarker<u8>
-        };
+        }
         impl NotifyTarget<u8> for N {
             fn on_set(&mut self, items: &[u8], at_marker: ItemMarker<u8>) {
                 assert_eq!(items, &[1,2,3]);
@@ -274,7 +281,7 @@ mod test {
             fn on_set(&mut self, _items: &[usize], _at_marker: ItemMarker<usize>) { }
             fn on_delete(&mut self, items: &[usize]) {
                 for i in items {
-                    assert_eq!(self.0[*i], false);
+                    assert!(!self.0[*i]);
                     self.0[*i] = true;
                 }
             }
@@ -294,17 +301,1558 @@ mod test {
         // Delete everything but the first and last characters
         list.notify(&mut notify).del_at(1, len - 2);
 
-        assert_eq!(notify.0[0], false);
-        assert_eq!(notify.0[SIZE-1], false);
+        assert!(!notify.0[0]);
+        assert!(!notify.0[SIZE-1]);
         for v in &notify.0[1..SIZE-1] {
-            assert_eq!(*v, true);
+            assert!(*v);
+        }
+    }
+
+    // Maps each item (which is its own key) to the last marker reported for it.
+    struct MarkerMap {
+        markers: Vec<ItemMarker<usize>>,
+        num_set: usize,
+    }
+    impl NotifyTarget<usize> for MarkerMap {
+        fn on_set(&mut self, items: &[usize], at_marker: ItemMarker<usize>) {
+            for i in items { self.markers[*i] = at_marker; }
+            self.num_set += 1;
+        }
+        fn on_delete(&mut self, items: &[usize]) {
+            for i in items { self.markers[*i] = ItemMarker::null(); }
+        }
+    }
+
+    #[test]
+    fn markers_survive_node_merges() {
+        const SIZE: usize = 500;
+        let mut notify = MarkerMap { markers: vec![ItemMarker::null(); SIZE], num_set: 0 };
+        let mut list = SkipList::new_from_iter_n(&mut notify, 0..SIZE);
+        notify.num_set = 0;
+
+        // Deleting runs which straddle node boundaries leaves pairs of
+        // under-full nodes next to each other, which should get merged.
+        let mut expected: Vec<usize> = (0..SIZE).collect();
+        let mut pos = 5;
+        while pos + 10 <= expected.len() {
+            list.notify(&mut notify).del_at(pos, 10);
+            expected.drain(pos..pos + 10);
+            pos += 5;
+        }
+        list.check();
+        assert!(list.eq_list(&expected));
+
+        // Deletes only call on_set when items are moved between nodes.
+        assert!(notify.num_set > 0);
+
+        for (pos, item) in expected.iter().enumerate() {
+            let marker = notify.markers[*item];
+            assert!(!marker.is_null());
+            let edit = unsafe { list.edit_at_marker_exact(&mut notify, marker, |i| i == item) }.unwrap();
+            assert_eq!(edit.user_position(), pos);
+        }
+    }
+
+
+
+    #[test]
+    fn bulk_construction() {
+        for &len in &[0, 1, 9, 10, 11, 99, 100, 101, 2500] {
+            let content: Vec<usize> = (0..len).collect();
+
+            let list = SkipList::new_from_iter(content.iter().copied());
+            check2(&list, &content);
+
+            let list: SkipList<usize> = content.iter().copied().collect();
+            check2(&list, &content);
+
+            let mut notify = MarkerMap { markers: vec![ItemMarker::null(); len], num_set: 0 };
+            let mut list = SkipList::new_from_iter_n(&mut notify, content.iter().copied());
+            list.check();
+            assert!(list.eq_list(&content));
+
+            // The head is left empty here, so iterating has to skip it.
+            let mut iter = list.iter();
+            for (pos, item) in content.iter().enumerate() {
+                assert_eq!(iter.size_hint(), (len - pos, Some(len - pos)));
+                assert_eq!(iter.next(), Some(item));
+            }
+            assert_eq!(iter.size_hint(), (0, Some(0)));
+            assert_eq!(iter.next(), None);
+
+            for (pos, item) in content.iter().enumerate() {
+                let marker = notify.markers[*item];
+                let edit = unsafe { list.edit_at_marker_exact(&mut notify, marker, |i| i == item) }.unwrap();
+                assert_eq!(edit.user_position(), pos);
+            }
+        }
+    }
+
+    #[test]
+    fn compact_keeps_contents() {
+        let mut list = SkipList::<u8>::new();
+        list.compact();
+        check(&list, &[]);
+
+        // Lots of small inserts in the middle of the list leave a lot of
+        // partially filled nodes.
+        let mut expected = Vec::new();
+        for i in 0..200 {
+            let pos = expected.len() / 2;
+            list.insert_at_slice(pos, &[i, i]);
+            expected.splice(pos..pos, [i, i].iter().copied());
+        }
+        list.compact();
+        check(&list, &expected);
+
+        // The compacted list should still be editable.
+        list.insert_at_slice(5, &[1,2,3]);
+        expected.splice(5..5, [1,2,3].iter().copied());
+        list.del_at(100, 50);
+        expected.drain(100..150);
+        check(&list, &expected);
+    }
+
+    #[test]
+    fn compact_updates_markers() {
+        const SIZE: usize = 500;
+        let mut notify = MarkerMap { markers: vec![ItemMarker::null(); SIZE], num_set: 0 };
+        let mut list = SkipList::<usize, MarkerMap>::new();
+        for i in 0..SIZE {
+            let pos = i / 2;
+            list.notify(&mut notify).insert_at(pos, iter::once(i));
+        }
+        let expected: Vec<usize> = list.iter().copied().collect();
+
+        notify.num_set = 0;
+        list.compact_n(&mut notify);
+        list.check();
+        assert!(list.eq_list(&expected));
+        assert!(notify.num_set > 0);
+
+        for (pos, item) in expected.iter().enumerate() {
+            let marker = notify.markers[*item];
+            let edit = unsafe { list.edit_at_marker_exact(&mut notify, marker, |i| i == item) }.unwrap();
+            assert_eq!(edit.user_position(), pos);
+        }
+    }
+
+    #[test]
+    fn node_pool_reuses_nodes() {
+        let mut list = SkipList::<usize>::new();
+        list.enable_node_pool();
+        let content: Vec<usize> = (0..1000).collect();
+
+        list.insert_at(0, content.iter().copied());
+        list.del_at(0, 1000);
+        check2(&list, &[] as &[usize]);
+        let pooled = list.num_pooled_nodes();
+        assert!(pooled > 0);
+
+        // Inserting again should pull nodes back out of the pool.
+        list.insert_at(0, content.iter().copied());
+        check2(&list, &content);
+        assert!(list.num_pooled_nodes() < pooled);
+
+        list.del_at(10, 500);
+        list.shrink_to_fit();
+        assert_eq!(list.num_pooled_nodes(), 0);
+        list.insert_at(10, content[10..510].iter().copied());
+        check2(&list, &content);
+    }
+
+    // Tracks the number of live node allocations, passing the actual
+    // allocations through to the global allocator.
+    #[derive(Default)]
+    struct CountingAlloc {
+        live: Cell<usize>,
+        total: Cell<usize>,
+    }
+    unsafe impl NodeAllocator for CountingAlloc {
+        fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+            self.live.set(self.live.get() + 1);
+            self.total.set(self.total.get() + 1);
+            Global.allocate(layout)
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.live.set(self.live.get() - 1);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let alloc = CountingAlloc::default();
+        let content: Vec<usize> = (0..1000).collect();
+
+        {
+            let mut list = SkipList::new_from_iter_in(content.iter().copied(), &alloc);
+            assert!(alloc.live.get() > 0);
+            list.insert_at(500, content.iter().copied());
+            list.del_at(500, 1000);
+            list.compact();
+            list.check();
+            assert!(list.eq_list(&content));
+        }
+        assert!(alloc.total.get() > 0);
+        assert_eq!(alloc.live.get(), 0);
+
+        let mut list = SkipList::<usize, (), &CountingAlloc>::new_in(&alloc);
+        list.enable_node_pool();
+        list.insert_at(0, content.iter().copied());
+        list.del_at(0, 1000);
+        assert!(alloc.live.get() > 0);
+        list.shrink_to_fit();
+        assert_eq!(alloc.live.get(), 0);
+    }
+
+    // An allocator which lives inside the list, rather than being borrowed.
+    struct OwnedAlloc(Rc<CountingAlloc>);
+    unsafe impl NodeAllocator for OwnedAlloc {
+        fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+            self.0.allocate(layout)
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn compact_with_owned_allocator() {
+        // Compacting frees the old nodes through the list's own allocator.
+        // This one is worth running under miri.
+        let counts = Rc::new(CountingAlloc::default());
+        {
+            let mut list = SkipList::new_from_iter_in(0..1000usize, OwnedAlloc(counts.clone()));
+            list.compact();
+            list.check();
+            assert!(list.iter().copied().eq(0..1000));
+        }
+        assert_eq!(counts.live.get(), 0);
+    }
+
+    #[test]
+    fn send_list_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SkipList<usize>>();
+        assert_send_sync::<SkipList<usize, MarkerMap>>();
+        assert_send_sync::<ListItemIter<usize>>();
+        assert_send_sync::<ItemMarker<usize>>();
+
+        let content: Vec<usize> = (0..1000).collect();
+        let mut list = SkipList::new_from_iter(content.iter().copied());
+
+        // Edit the list on another thread, then hand it back.
+        list = std::thread::spawn(move || {
+            list.del_at(0, 500);
+            list
+        }).join().unwrap();
+        check2(&list, &content[500..]);
+
+        // And read it concurrently from a few threads.
+        std::thread::scope(|scope| {
+            let list = &list;
+            let handles: Vec<_> = (0..4).map(|_| scope.spawn(move || {
+                list.iter().copied().collect::<Vec<usize>>()
+            })).collect();
+
+            for h in handles {
+                assert_eq!(h.join().unwrap(), &content[500..]);
+            }
+        });
+    }
+
+    #[test]
+    fn shared_list_readers() {
+        let mut shared = SharedList::new(SkipList::<usize>::new_from_iter(0..1000));
+        let mut reader = shared.reader();
+
+        shared.replace_at_slice(0, 1, &[999]);
+        shared.del_at(10, 5);
+        assert_eq!(shared.iter().next(), Some(&999));
+        assert_eq!(reader.read().iter().next(), Some(&0));
+        assert_eq!(reader.read().len_items(), 1000);
+
+        shared.publish();
+        let handles: Vec<_> = (0..4).map(|_| {
+            let mut reader = reader.clone();
+            std::thread::spawn(move || {
+                let list = reader.read();
+                list.check();
+                list.iter().copied().collect::<Vec<usize>>()
+            })
+        }).collect();
+        // Publishing doesn't wait for the threads above. They carry on with
+        // whichever version they started reading.
+        shared.insert_at_slice(0, &[5, 6, 7]);
+        shared.publish();
+
+        for h in handles {
+            let contents = h.join().unwrap();
+            assert!(contents.len() == 995 || contents.len() == 998);
+        }
+        // Both copies end up with every edit.
+        shared.insert_at_slice(0, &[1]);
+        shared.publish();
+        let mut expected: Vec<usize> = vec![1, 5, 6, 7, 999];
+        expected.extend((1..10).chain(15..1000));
+        assert!(reader.read().eq_list(&expected));
+        assert!(shared.eq_list(&expected));
+        reader.read().check();
+    }
+
+    #[test]
+    fn shared_list_publish_doesnt_wait() {
+        use std::sync::atomic::{AtomicIsize, Ordering};
+        static LIVE: AtomicIsize = AtomicIsize::new(0);
+        #[derive(Debug)]
+        struct Counted(u32);
+        impl Counted {
+            fn new(n: u32) -> Self { LIVE.fetch_add(1, Ordering::SeqCst); Counted(n) }
+        }
+        impl Clone for Counted {
+            fn clone(&self) -> Self { Counted::new(self.0) }
+        }
+        impl Drop for Counted {
+            fn drop(&mut self) { LIVE.fetch_sub(1, Ordering::SeqCst); }
+        }
+        impl ListItem for Counted {}
+
+        let live = || LIVE.load(Ordering::SeqCst) as usize;
+        let mut shared = SharedList::new(SkipList::new_from_iter((0..100).map(Counted::new)));
+        let mut reader = shared.reader();
+        assert_eq!(live(), 100);
+
+        {
+            // Publishing while a reader is mid-read would deadlock if it
+            // waited for the reader.
+            let guard = reader.read();
+            for i in 0..3 {
+                shared.insert_at_slice(0, &[Counted::new(1000 + i)]);
+                shared.publish();
+            }
+            assert_eq!(guard.len_items(), 100);
+            assert_eq!(guard.iter().next().map(|c| c.0), Some(0));
+            guard.check();
+            // The reader's version is kept until it's done.
+            assert!(live() > 103 + 102 + 100);
+        }
+        assert_eq!(reader.read().len_items(), 103);
+        assert_eq!(reader.read().iter().next().map(|c| c.0), Some(1002));
+
+        // Once nobody is reading them, retired versions are freed, and the
+        // last one is reused for the writer's next copy.
+        shared.del_at(0, 1);
+        shared.publish();
+        shared.del_at(0, 1);
+        assert_eq!(live(), 102 + 101);
+        assert!(shared.iter().map(|c| c.0).eq(iter::once(1000).chain(0..100)));
+        shared.publish();
+        assert!(reader.read().iter().map(|c| c.0).eq(iter::once(1000).chain(0..100)));
+
+        drop(reader);
+        drop(shared);
+        assert_eq!(live(), 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let list = SkipList::<usize>::new_from_iter(0..1000);
+        let json = serde_json::to_string(&list).unwrap();
+        let expected: Vec<usize> = (0..1000).collect();
+        assert_eq!(json, serde_json::to_string(&expected).unwrap());
+
+        let decoded: SkipList<usize> = serde_json::from_str(&json).unwrap();
+        decoded.check();
+        assert!(decoded.eq_list(&expected));
+
+        assert!(serde_json::from_str::<SkipList<usize>>("[1, 2, \"x\"]").is_err());
+    }
+
+    fn encoded_layout(list: &SkipList<u32>) -> Vec<u8> {
+        let mut bytes = vec![];
+        list.encode(&mut bytes, &U32Codec).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn recorded_session_replays() {
+        let mut list = SkipList::<u32>::new();
+        list.start_recording(42);
+        let mut rng = SmallRng::seed_from_u64(7);
+        let mut len = 0;
+        for i in 0..300 {
+            list.insert_at_slice(rng.gen_range(0, len + 1), &[i as u32; 3]);
+            len += 3;
+            if i % 4 == 0 { list.del_at(rng.gen_range(0, len - 1), 2); len -= 2; }
+            if i % 7 == 0 { list.replace_at_slice(rng.gen_range(0, len), 1, &[1000, 1001]); len += 1; }
+            if i % 9 == 0 { list.modify_item_after(rng.gen_range(0, len), |item, _| *item += 1); }
+        }
+
+        let recorder = list.stop_recording().unwrap();
+        let replayed = recorder.replay();
+        replayed.check();
+        assert!(replayed.eq_list(&Vec::from(&list)));
+        // The encoding includes the size of every node.
+        assert_eq!(encoded_layout(&replayed), encoded_layout(&list));
+
+        let mut applied = SkipList::new();
+        for op in recorder.ops() { applied.apply(op); }
+        assert!(applied.eq_list(&Vec::from(&list)));
+
+        // Edits after recording stops aren't logged.
+        list.del_at(0, 1);
+        assert!(list.recorder().is_none());
+    }
+
+    #[test]
+    fn recording_covers_every_edit() {
+        // The base list has a layout the bulk loader wouldn't make.
+        let mut list = SkipList::<u32>::new_from_iter(0..500);
+        for i in 0..50 { list.del_at(i * 7, 3); }
+        list.start_recording(3);
+
+        list.insert_at_slice(10, &[1, 2, 3]);
+        {
+            let (mut edit, _) = list.edit(100);
+            edit.del(5);
+            edit.insert(7);
+            edit.modify_current_item(|item| *item = 8);
+            edit.replace(2, [9, 9, 9].iter().copied());
+            edit.commit();
+        }
+        list.apply_batch(&[Op::Delete { pos: 20, count: 4 }, Op::Insert { pos: 30, items: vec![5; 20] }]);
+        let mut history = History::new();
+        history.apply(&mut list, Op::Replace { pos: 40, removed: 10, items: vec![6] });
+        history.undo(&mut list);
+        let _: Result<(), ()> = list.transaction(|tx| {
+            tx.del_at(0, 50);
+            Err(())
+        });
+        let mut edit = list.multi_edit(vec![5, 50, 150]);
+        edit.insert_slice(&[4, 4]);
+        edit.del(1);
+        drop(edit);
+
+        let recorder = list.recorder().unwrap();
+        assert_eq!(recorder.base().len(), 350);
+        let replayed = recorder.replay();
+        replayed.check();
+        assert!(replayed.eq_list(&Vec::from(&list)));
+        assert_eq!(encoded_layout(&replayed), encoded_layout(&list));
+    }
+
+    #[test]
+    fn undo_redo() {
+        const SIZE: usize = 1000;
+        let mut notify = MarkerMap { markers: vec![ItemMarker::null(); SIZE + 10], num_set: 0 };
+        let mut list = SkipList::new_from_iter_n(&mut notify, 0..SIZE);
+        let mut history = History::new();
+        let original: Vec<usize> = (0..SIZE).collect();
+
+        history.apply_n(&mut list, &mut notify, Op::Delete { pos: 100, count: 500 });
+        history.begin_group();
+        history.apply_n(&mut list, &mut notify, Op::Insert { pos: 0, items: vec![SIZE, SIZE + 1] });
+        history.apply_n(&mut list, &mut notify, Op::Replace { pos: 10, removed: 3, items: vec![SIZE + 2] });
+        history.apply_n(&mut list, &mut notify, Op::Modify { pos: 20, item: SIZE + 3 });
+        history.end_group();
+        let edited = Vec::from(&list);
+
+        assert!(history.undo_n(&mut list, &mut notify));
+        list.check();
+        assert!(history.undo_n(&mut list, &mut notify));
+        assert!(!history.undo_n(&mut list, &mut notify));
+        assert!(list.eq_list(&original));
+        for (pos, item) in original.iter().enumerate() {
+            let marker = notify.markers[*item];
+            let edit = unsafe { list.edit_at_marker_exact(&mut notify, marker, |i| i == item) }.unwrap();
+            assert_eq!(edit.user_position(), pos);
+        }
+
+        assert!(history.redo_n(&mut list, &mut notify));
+        assert!(history.redo_n(&mut list, &mut notify));
+        assert!(!history.redo_n(&mut list, &mut notify));
+        list.check();
+        assert!(list.eq_list(&edited));
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the history")]
+    fn undo_rejects_outside_edits() {
+        let mut list = SkipList::<u32>::new_from_iter(0..100);
+        let mut history = History::new();
+        history.apply(&mut list, Op::Delete { pos: 10, count: 5 });
+        // Same size, but the inverse op would still put back the wrong item.
+        list.modify_item_after(3, |item, _| *item = 1000);
+        history.undo(&mut list);
+    }
+
+    #[test]
+    fn undo_split_insert() {
+        let original: Vec<u8> = vec![5, 3, 7, 2, 6];
+        let mut list = SkipList::<SizedItem>::new_from_iter(original.iter().map(|x| SizedItem(*x)));
+        let mut history = History::new();
+
+        // Each insert lands inside an item. The last one inserts nothing,
+        // but still splits the item.
+        history.apply(&mut list, Op::Insert { pos: 2, items: vec![SizedItem(1), SizedItem(4)] });
+        history.apply(&mut list, Op::Insert { pos: 9, items: vec![SizedItem(2)] });
+        history.apply(&mut list, Op::Insert { pos: 17, items: vec![] });
+        let edited = [2u8, 1, 4, 2, 2, 1, 3, 2, 5, 2, 6];
+        list.check();
+        assert!(list.eq_list(&edited));
+
+        while history.undo(&mut list) {}
+        list.check();
+        assert!(list.eq_list(&original));
+
+        while history.redo(&mut list) {}
+        list.check();
+        assert!(list.eq_list(&edited));
+    }
+
+    #[test]
+    fn transaction_rollback() {
+        let original: Vec<usize> = (0..1000).collect();
+        let mut list = SkipList::new_from_iter(0..1000);
+
+        let result: Result<(), &str> = list.transaction(|tx| {
+            tx.del_at(100, 500);
+            tx.insert_at_slice(0, &[5, 6, 7]);
+            assert_eq!(tx.len_items(), 503);
+            Err("rejected")
+        });
+        assert_eq!(result, Err("rejected"));
+        list.check();
+        assert!(list.eq_list(&original));
+
+        let result: Result<usize, ()> = list.transaction(|tx| {
+            tx.del_at(0, 10);
+            Ok(tx.len_items())
+        });
+        assert_eq!(result, Ok(990));
+        assert!(list.eq_list(&original[10..]));
+
+        // Panicking rolls back too, and the panic is passed on.
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _: Result<(), ()> = list.transaction(|tx| {
+                tx.replace_at_slice(10, 20, &[1, 2]);
+                tx.insert_at_slice(500, &[3; 100]);
+                panic!("validation failed");
+            });
+        }));
+        assert_eq!(panicked.unwrap_err().downcast_ref::<&str>(), Some(&"validation failed"));
+        list.check();
+        assert!(list.eq_list(&original[10..]));
+    }
+
+    #[test]
+    fn apply_batch_matches_apply() {
+        // Build a batch against the original list. Positions only move
+        // forwards, and each op starts after anything the last op removed.
+        let mut rng = SmallRng::seed_from_u64(11);
+        let mut ops = vec![];
+        let mut pos = 0;
+        while pos < 1000 {
+            let op = match rng.gen_range(0, 4) {
+                0 => Op::Insert { pos, items: vec![7; rng.gen_range(1, 30)] },
+                1 => Op::Delete { pos, count: rng.gen_range(0, 30).min(1000 - pos) },
+                2 => Op::Replace { pos, removed: rng.gen_range(0, 30).min(1000 - pos), items: vec![8; rng.gen_range(0, 30)] },
+                _ => Op::Modify { pos, item: 9 },
+            };
+            match &op {
+                Op::Insert { .. } => {},
+                Op::Delete { count, .. } => pos += count,
+                Op::Replace { removed, .. } => pos += removed,
+                // So a later insert can't land in front of the modified item.
+                Op::Modify { .. } => pos += 1,
+            }
+            if rng.gen_bool(0.8) { pos += rng.gen_range(0, 50); }
+            ops.push(op);
+        }
+
+        // Applying the ops last to first means no op moves the ones after it.
+        let mut expected = SkipList::<u32>::new_from_iter(0..1000);
+        for op in ops.iter().rev() { expected.apply(op); }
+
+        let mut list = SkipList::<u32>::new_from_iter(0..1000);
+        list.apply_batch(&ops);
+        list.check();
+        assert!(list.eq_list(&Vec::from(&expected)));
+
+        // Inserts at the same position keep their order, and go before a
+        // delete which starts there.
+        let mut list = SkipList::<u32>::new_from_iter(0..10);
+        list.apply_batch(&[
+            Op::Insert { pos: 2, items: vec![100] },
+            Op::Insert { pos: 2, items: vec![101, 102] },
+            Op::Delete { pos: 2, count: 3 },
+            Op::Replace { pos: 6, removed: 1, items: vec![103, 104] },
+            Op::Insert { pos: 10, items: vec![105] },
+        ]);
+        assert!(list.eq_list(&[0, 1, 100, 101, 102, 5, 103, 104, 7, 8, 9, 105]));
+    }
+
+    #[test]
+    #[should_panic(expected = "sorted")]
+    fn apply_batch_rejects_unsorted_ops() {
+        let mut list = SkipList::<u32>::new_from_iter(0..1000);
+        list.apply_batch(&[Op::Delete { pos: 500, count: 10 }, Op::Insert { pos: 3, items: vec![1, 2] }]);
+    }
+
+    #[test]
+    fn seek_to_matches_fresh_edit() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let items: Vec<SizedItem> = (0..2000).map(|_| SizedItem(rng.gen_range(0, 5))).collect();
+        let mut list = SkipList::new_from_iter(items.iter().copied());
+        let len = list.len_user();
+
+        let (mut edit, _) = list.edit(0);
+        let mut pos = 0;
+        for i in 0..2000 {
+            // Mostly local moves in both directions, with the odd long jump.
+            pos = if i % 50 == 0 {
+                rng.gen_range(0, len + 1)
+            } else {
+                (pos + rng.gen_range(0, 40)).saturating_sub(20).min(len)
+            };
+            let offset = edit.seek_to(pos);
+            assert_eq!(edit.user_position(), pos);
+            if offset > 0 {
+                assert!(edit.current_item().unwrap().get_usersize() > offset);
+            }
+        }
+    }
+
+    #[test]
+    fn local_edits_after_move() {
+        // Nearby edits reuse the cursor from the previous edit. That cursor
+        // points into the list's inline head, so it can't survive a move.
+        let mut list = SkipList::<usize>::new();
+        for i in 0..500 { list.insert_at(i / 3, iter::once(i)); }
+        let mut expected: Vec<usize> = list.iter().copied().collect();
+
+        let mut boxed = Box::new(list);
+        for i in 0..100 {
+            boxed.del_at(i, 1);
+            expected.remove(i);
+            boxed.insert_at(i + 1, iter::once(i));
+            expected.insert(i + 1, i);
+        }
+        boxed.check();
+        assert!(boxed.eq_list(&expected));
+    }
+
+    #[test]
+    fn user_position_after_resize() {
+        let mut list = SkipList::<SizedItem>::new_from_iter((0..300usize).map(|i| SizedItem(1 + (i % 5) as u8)));
+        let mut expected: Vec<u8> = (0..300usize).map(|i| 1 + (i % 5) as u8).collect();
+
+        for i in 0..100 {
+            let pos: usize = expected[..i * 2].iter().map(|x| *x as usize).sum();
+            {
+                let mut edit = list.edit_exact(pos);
+                edit.modify_current_item(|item| item.0 += 3);
+                let len = expected[i * 2] as usize + 3;
+                assert_eq!(edit.user_position(), pos + len);
+                edit.modify_prev_item(|item| item.0 -= 1);
+                assert_eq!(edit.user_position(), pos + len - 1);
+                edit.commit();
+            }
+            expected[i * 2] += 2;
+
+            // The next lookup searches from the resized edit's cursor.
+            list.modify_item_after(pos + 1, |item, _| item.0 += 1);
+            let j = (0..).find(|&j| expected[..=j].iter().map(|x| *x as usize).sum::<usize>() > pos + 1).unwrap();
+            expected[j] += 1;
+        }
+        list.check();
+        assert!(list.eq_list(&expected));
+    }
+
+    #[test]
+    fn backspace() {
+        let mut rng = SmallRng::seed_from_u64(5);
+        let items: Vec<SizedItem> = (0..1000).map(|_| SizedItem(rng.gen_range(0, 4))).collect();
+        let mut expected = items.clone();
+        let mut list = SkipList::new_from_iter(items.iter().copied());
+
+        // Walk the cursor forward, backspacing as we go. Zero sized items
+        // make positions ambiguous, so track the expected index separately.
+        let (mut edit, _) = list.edit(0);
+        let mut index = 0;
+        while index < expected.len() {
+            if rng.gen_range(0, 3) == 0 && index > 0 {
+                let n = rng.gen_range(1, index.min(5) + 1);
+                edit.del_backward(n);
+                index -= n;
+                expected.drain(index..index + n);
+            } else {
+                edit.advance_item();
+                index += 1;
+            }
+            assert_eq!(edit.user_position(), SizedItem::userlen_of_slice(&expected[..index]));
+        }
+
+        while edit.retreat_item() { index -= 1; }
+        assert_eq!(index, 0);
+        assert_eq!(edit.retreat_by(0), 0);
+
+        list.check();
+        assert!(list.eq_list(&expected));
+    }
+
+    #[test]
+    fn insert_returns_markers() {
+        const SIZE: usize = 1000;
+        let mut notify = MarkerMap { markers: vec![ItemMarker::null(); SIZE + 20], num_set: 0 };
+        let mut list = SkipList::new_from_iter_n(&mut notify, 0..SIZE);
+
+        let inserted = list.notify(&mut notify).insert_at_marked(300, SIZE..SIZE + 20);
+        assert_eq!(inserted.range, 300..320);
+        assert_eq!(inserted.markers.len(), 20);
+        for (i, marker) in inserted.markers.iter().enumerate() {
+            assert_eq!(*marker, notify.markers[SIZE + i]);
+        }
+
+        // Inserting in the middle of an item splits it.
+        let mut list = SkipList::new_from_slice(&[SizedItem(3), SizedItem(5)]);
+        let (mut edit, offset) = list.edit(5);
+        assert_eq!(offset, 2);
+        let inserted = edit.insert_between_iter_marked(offset, [SizedItem(1), SizedItem(4)].iter().copied());
+        assert_eq!(inserted.range, 5..10);
+        assert_eq!(inserted.markers.len(), 2);
+        assert_eq!(edit.user_position(), 13);
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Keyed(u32);
+    impl ListItem for Keyed {}
+    impl KeyedItem<u32> for Keyed {
+        fn key(&self) -> u32 { self.0 }
+    }
+
+    #[test]
+    fn marker_index() {
+        let mut index = MarkerIndex::new();
+        let mut list = SkipList::new_from_iter_n(&mut index, (0..1000).map(Keyed));
+        assert_eq!(index.len(), 1000);
+
+        list.notify(&mut index).del_at(100, 200);
+        list.notify(&mut index).insert_at(0, (5000..5010).map(Keyed));
+        assert_eq!(index.len(), 810);
+        assert!(!index.contains_key(&150));
+
+        unsafe {
+            assert_eq!(index.position_of(&list, &5003), Some(3));
+            assert_eq!(index.position_of(&list, &50), Some(60));
+            assert_eq!(index.position_of(&list, &300), Some(110));
+            assert_eq!(index.position_of(&list, &150), None);
+
+            let mut edit = index.edit_at(&mut list, &300).unwrap();
+            assert_eq!(edit.current_item(), Some(&Keyed(300)));
+            edit.del(1);
+        }
+        assert!(!index.contains_key(&300));
+        list.check();
+    }
+
+    #[test]
+    fn anchors() {
+        let mut anchors = Anchors::new();
+        let mut list = SkipList::new_from_iter_n(&mut anchors, 0..100u32);
+
+        let left = anchors.add(50, Bias::Left);
+        let right = anchors.add(50, Bias::Right);
+        let end = anchors.add(100, Bias::Right);
+
+        // Inserting at the anchors separates them.
+        list.notify(&mut anchors).insert_at(50, 1000..1005);
+        assert_eq!(anchors.resolve(left), Some(50));
+        assert_eq!(anchors.resolve(right), Some(55));
+
+        // Edits elsewhere move them along.
+        list.notify(&mut anchors).insert_at(0, 2000..2100);
+        list.notify(&mut anchors).del_at(10, 20);
+        list.notify(&mut anchors).insert_at(140, 3000..3100);
+        assert_eq!(anchors.resolve(left), Some(130));
+        assert_eq!(anchors.resolve(right), Some(135));
+        assert_eq!(anchors.resolve(end), Some(285));
+
+        // Deleting the items around an anchor leaves it where they were, and
+        // it keeps following edits from there.
+        list.notify(&mut anchors).del_at(125, 11);
+        assert_eq!(anchors.resolve(left), Some(125));
+        assert_eq!(anchors.resolve(right), Some(125));
+        list.notify(&mut anchors).insert_at(125, 4000..4003);
+        list.notify(&mut anchors).del_at(0, 5);
+        assert_eq!(anchors.resolve(left), Some(120));
+        assert_eq!(anchors.resolve(right), Some(123));
+
+        assert!(anchors.remove(right));
+        assert!(!anchors.remove(right));
+        assert_eq!(anchors.resolve(right), None);
+        assert_eq!(anchors.resolve(left), Some(120));
+        assert_eq!(anchors.len(), 2);
+    }
+
+    #[test]
+    fn anchors_across_splits() {
+        let mut anchors = Anchors::new();
+        let mut list = SkipList::new_from_iter_n(&mut anchors, into_iter::<SizedItem, _>(&[4, 4, 4]));
+        let left = anchors.add(8, Bias::Left);
+        let right = anchors.add(4, Bias::Right);
+
+        // Splitting an item to insert inside it doesn't move anchors at its
+        // edges.
+        list.notify(&mut anchors).insert_at(6, into_iter(&[1, 1]));
+        assert_eq!(anchors.resolve(left), Some(10));
+        assert_eq!(anchors.resolve(right), Some(4));
+        list.notify(&mut anchors).del_at(0, 1);
+        list.notify(&mut anchors).insert_at(0, into_iter(&[3]));
+        assert_eq!(anchors.resolve(left), Some(9));
+        assert_eq!(anchors.resolve(right), Some(3));
+
+        // Replacing an item keeps the anchors at its ends there.
+        let after = anchors.add(5, Bias::Left);
+        list.notify(&mut anchors).replace_at(3, 1, into_iter(&[6]));
+        assert_eq!(anchors.resolve(right), Some(3));
+        assert_eq!(anchors.resolve(after), Some(9));
+        assert_eq!(anchors.resolve(left), Some(13));
+    }
+
+    #[test]
+    fn anchors_match_model() {
+        let mut anchors = Anchors::new();
+        let mut list = SkipList::new_from_iter_n(&mut anchors, 0..100u32);
+
+        // Each anchor's bias and position, or None once removed.
+        let mut model: Vec<(Anchor, Bias, Option<usize>)> = vec![];
+        let mut rng = SmallRng::seed_from_u64(21);
+        // Move the anchors for an insert of n items at pos.
+        let insert = |model: &mut Vec<(Anchor, Bias, Option<usize>)>, pos: usize, n: usize| {
+            for (_, bias, p) in model.iter_mut() {
+                if let Some(p) = p {
+                    if *p > pos || (*p == pos && *bias == Bias::Right) { *p += n; }
+                }
+            }
+        };
+        let delete = |model: &mut Vec<(Anchor, Bias, Option<usize>)>, pos: usize, n: usize| {
+            for (_, _, p) in model.iter_mut() {
+                if let Some(p) = p {
+                    if *p > pos + n { *p -= n; } else if *p > pos { *p = pos; }
+                }
+            }
+        };
+
+        for _ in 0..1000 {
+            let len = list.len_items();
+            match rng.gen_range(0, 5) {
+                0 => {
+                    let pos = rng.gen_range(0, len + 1);
+                    let bias = if rng.gen() { Bias::Left } else { Bias::Right };
+                    model.push((anchors.add(pos, bias), bias, Some(pos)));
+                }
+                1 if !model.is_empty() => {
+                    let idx = rng.gen_range(0, model.len());
+                    assert_eq!(anchors.remove(model[idx].0), model[idx].2.is_some());
+                    model[idx].2 = None;
+                }
+                2 if len > 0 => {
+                    let pos = rng.gen_range(0, len);
+                    let n = rng.gen_range(1, (len - pos).min(20) + 1);
+                    list.notify(&mut anchors).del_at(pos, n);
+                    delete(&mut model, pos, n);
+                }
+                3 if len > 0 => {
+                    // Replaced items keep the anchors between them.
+                    let pos = rng.gen_range(0, len);
+                    let removed = rng.gen_range(1, (len - pos).min(20) + 1);
+                    let inserted = rng.gen_range(0, 20);
+                    list.notify(&mut anchors).replace_at_slice(pos, removed, &vec![1; inserted]);
+                    if inserted >= removed {
+                        insert(&mut model, pos + removed, inserted - removed);
+                    } else {
+                        delete(&mut model, pos + inserted, removed - inserted);
+                    }
+                }
+                _ => {
+                    let pos = rng.gen_range(0, len + 1);
+                    let n = rng.gen_range(1, 20);
+                    list.notify(&mut anchors).insert_at_slice(pos, &vec![0; n]);
+                    insert(&mut model, pos, n);
+                }
+            }
+
+            for (anchor, _, p) in &model {
+                assert_eq!(anchors.resolve(*anchor), *p);
+            }
+            assert_eq!(anchors.len(), model.iter().filter(|(_, _, p)| p.is_some()).count());
         }
     }
 
+    #[test]
+    fn composed_notify_targets() {
+        let mut index = MarkerIndex::new();
+        let set = Cell::new(0);
+        let deleted = Cell::new(0);
+        let counter = notify_fn(|items: &[Keyed], _| set.set(set.get() + items.len()),
+            |items: &[Keyed]| deleted.set(deleted.get() + items.len()));
+        {
+            let mut notify = (&mut index, Some(counter));
+            let mut list = SkipList::new_from_iter_n(&mut notify, (0..100).map(Keyed));
+            list.notify(&mut notify).del_at(10, 5);
+        }
 
+        assert_eq!(index.len(), 95);
+        assert_eq!(set.get(), 100);
+        assert_eq!(deleted.get(), 5);
+    }
+
+    // Rebuilds the list's contents from insert / remove events alone, and
+    // tracks where each item lives from insert / relocate events.
+    #[derive(Default)]
+    struct Mirror {
+        items: Vec<u32>,
+        markers: std::collections::HashMap<u32, ItemMarker<u32>>,
+        relocated: usize,
+    }
+    impl NotifyTarget<u32> for Mirror {
+        fn on_set(&mut self, _items: &[u32], _at_marker: ItemMarker<u32>) {}
+        fn on_delete(&mut self, _items: &[u32]) {}
+
+        fn on_insert(&mut self, userpos: usize, items: &[u32], at_marker: ItemMarker<u32>) {
+            self.items.splice(userpos..userpos, items.iter().copied());
+            for i in items { self.markers.insert(*i, at_marker); }
+        }
+        fn on_remove(&mut self, userpos: usize, items: &[u32]) {
+            let removed: Vec<u32> = self.items.drain(userpos..userpos + items.len()).collect();
+            assert_eq!(removed, items);
+            for i in items { self.markers.remove(i); }
+        }
+        fn on_relocate(&mut self, items: &[u32], from: ItemMarker<u32>, to: ItemMarker<u32>) {
+            for i in items {
+                let old = self.markers.insert(*i, to).unwrap();
+                if from != ItemMarker::null() { assert_eq!(old, from); }
+            }
+            self.relocated += items.len();
+        }
+    }
+
+    #[test]
+    fn change_events() {
+        let mut mirror = Mirror::default();
+        let mut list = SkipList::new_from_iter_n(&mut mirror, 0..100);
+        assert_eq!(mirror.items, (0..100).collect::<Vec<u32>>());
+
+        let mut rng = SmallRng::seed_from_u64(7);
+        let mut next = 100;
+        for _ in 0..300 {
+            let len = list.len_items();
+            let pos = rng.gen_range(0, len + 1);
+            let count = rng.gen_range(0, 30);
+            let new_items: Vec<u32> = (next..next + count as u32).collect();
+            next += count as u32;
+            match rng.gen_range(0, 3) {
+                0 => list.notify(&mut mirror).insert_at_slice(pos, &new_items),
+                1 => list.notify(&mut mirror).del_at(pos, count.min(len - pos)),
+                _ => list.notify(&mut mirror).replace_at_slice(pos, count.min(len - pos), &new_items),
+            }
+            assert!(list.eq_list(&mirror.items));
+        }
+        // Inserting into full nodes moves existing items around.
+        assert!(mirror.relocated > 0);
+
+        list.compact_n(&mut mirror);
+        assert!(list.eq_list(&mirror.items));
+        let markers = std::mem::take(&mut mirror.markers);
+        for (item, marker) in markers {
+            let edit = unsafe { list.edit_at_marker_exact(&mut mirror, marker, |i| *i == item) };
+            assert!(edit.is_some());
+        }
+    }
+
+    // Like Mirror, but for items bigger than 1, so inserts can split them.
+    #[derive(Default)]
+    struct SizedMirror {
+        items: Vec<SizedItem>,
+        splits: usize,
+        relocated: usize,
+    }
+    impl NotifyTarget<SizedItem> for SizedMirror {
+        fn on_set(&mut self, _items: &[SizedItem], _at_marker: ItemMarker<SizedItem>) {}
+        fn on_delete(&mut self, _items: &[SizedItem]) {}
+
+        fn on_insert(&mut self, userpos: usize, items: &[SizedItem], _at_marker: ItemMarker<SizedItem>) {
+            let idx = vec_find_userpos(&self.items, userpos);
+            self.items.splice(idx..idx, items.iter().copied());
+        }
+        fn on_remove(&mut self, userpos: usize, items: &[SizedItem]) {
+            let idx = vec_find_userpos(&self.items, userpos);
+            let removed: Vec<SizedItem> = self.items.drain(idx..idx + items.len()).collect();
+            assert_eq!(removed, items);
+        }
+        fn on_relocate(&mut self, items: &[SizedItem], _from: ItemMarker<SizedItem>, _to: ItemMarker<SizedItem>) {
+            self.relocated += items.len();
+        }
+        fn on_split(&mut self, userpos: usize, item: &SizedItem, offset: usize) {
+            // The item hasn't been touched yet, and is about to be removed.
+            let idx = vec_find_userpos(&self.items, userpos);
+            assert_eq!(&self.items[idx], item);
+            assert!(offset > 0 && offset < item.get_usersize());
+            self.splits += 1;
+        }
+    }
+
+    #[test]
+    fn change_events_with_splits() {
+        let mut mirror = SizedMirror::default();
+        let mut list = SkipList::new_from_iter_n(&mut mirror, (0..100u8).map(|i| SizedItem(1 + i % 9)));
+        assert!(list.eq_list(&mirror.items));
+
+        let mut rng = SmallRng::seed_from_u64(9);
+        for _ in 0..300 {
+            let len = list.len_user();
+            let new_items: Vec<SizedItem> = (0..rng.gen_range(0, 20)).map(|_| SizedItem(rng.gen_range(1, 6))).collect();
+            match rng.gen_range(0, 3) {
+                // Anywhere, including inside an item.
+                0 => list.notify(&mut mirror).insert_at(rng.gen_range(0, len + 1), new_items.into_iter()),
+                // Deletes and replaces have to start at an item boundary.
+                n => {
+                    let idx = rng.gen_range(0, mirror.items.len() + 1);
+                    let pos = mirror.items[..idx].iter().map(|i| i.get_usersize()).sum();
+                    let count = rng.gen_range(0, 20).min(mirror.items.len() - idx);
+                    if n == 1 {
+                        list.notify(&mut mirror).del_at(pos, count);
+                    } else {
+                        list.notify(&mut mirror).replace_at(pos, count, new_items.into_iter());
+                    }
+                }
+            }
+            assert!(list.eq_list(&mirror.items));
+        }
+        assert!(mirror.splits > 0);
+        assert!(mirror.relocated > 0);
+
+        list.compact_n(&mut mirror);
+        assert!(list.eq_list(&mirror.items));
+        list.check();
+    }
+
+    #[test]
+    fn split_events() {
+        struct Splits(Vec<(usize, u8, usize)>);
+        impl NotifyTarget<SizedItem> for Splits {
+            fn on_set(&mut self, _items: &[SizedItem], _at_marker: ItemMarker<SizedItem>) {}
+            fn on_delete(&mut self, _items: &[SizedItem]) {}
+            fn on_split(&mut self, userpos: usize, item: &SizedItem, offset: usize) {
+                self.0.push((userpos, item.0, offset));
+            }
+        }
+
+        let mut splits = Splits(vec![]);
+        let mut list = SkipList::new_from_iter_n(&mut splits, into_iter(&[2, 5]));
+        list.notify(&mut splits).insert_at(5, into_iter(&[1]));
+        list.notify(&mut splits).insert_at(2, into_iter(&[1]));
+        assert_eq!(splits.0, vec![(2, 5, 3)]);
+    }
+
+    #[test]
+    fn batched_notifications() {
+        struct Log(Vec<Batch<u32>>);
+        impl BatchTarget<u32> for Log {
+            fn on_batch(&mut self, batch: Batch<u32>) { self.0.push(batch); }
+        }
+
+        let mut batched = Batched::new(Log(vec![]));
+        let mut list = SkipList::new_from_iter_n(&mut batched, 0..10);
+        assert_eq!(batched.target().0.len(), 1);
+        assert_eq!(batched.target().0[0].changes, vec![Change::Insert { pos: 0, items: (0..10).collect() }]);
+
+        // A big insert touches lots of nodes, but arrives as one change.
+        list.notify(&mut batched).insert_at_slice(5, &(100..400).collect::<Vec<u32>>());
+        let batch = batched.target_mut().0.pop().unwrap();
+        assert_eq!(batch.changes, vec![Change::Insert { pos: 5, items: (100..400).collect() }]);
+        assert!(batch.markers.len() > 1);
+        assert!(batched.target().0.len() == 1);
+
+        list.notify(&mut batched).del_at(3, 30);
+        let batch = batched.target_mut().0.pop().unwrap();
+        let mut removed = vec![3, 4];
+        removed.extend(100..128);
+        assert_eq!(batch.changes, vec![Change::Remove { pos: 3, items: removed }]);
+
+        // Edits made through an Edit are delivered on commit.
+        let (mut edit, _) = list.edit_n(&mut batched, 10);
+        for _ in 0..3 { edit.del_backward(1); }
+        edit.commit();
+        let batch = batched.target_mut().0.pop().unwrap();
+        assert_eq!(batch.changes, vec![Change::Remove { pos: 7, items: vec![132, 133, 134] }]);
+        assert!(batched.pending().is_empty());
+
+        // An edit from Batched::edit flushes when it's dropped, too.
+        {
+            let (mut edit, _) = batched.edit(&mut list, 0);
+            edit.del(2);
+            edit.insert(1000);
+        }
+        assert!(batched.pending().is_empty());
+        let batch = batched.target_mut().0.pop().unwrap();
+        assert_eq!(batch.changes, vec![
+            Change::Remove { pos: 0, items: vec![0, 1] },
+            Change::Insert { pos: 0, items: vec![1000] },
+        ]);
+    }
+
+    #[test]
+    fn batched_mirror_across_splits() {
+        // Each batch is enough to keep a copy of the list up to date, even
+        // when inserts split items.
+        fn apply(mirror: &mut Vec<SizedItem>, batch: Batch<SizedItem>) {
+            for change in batch.changes {
+                match change {
+                    Change::Insert { pos, items } => {
+                        let idx = vec_find_userpos(mirror, pos);
+                        mirror.splice(idx..idx, items);
+                    }
+                    Change::Remove { pos, items } => {
+                        let idx = vec_find_userpos(mirror, pos);
+                        let removed: Vec<SizedItem> = mirror.splice(idx..idx + items.len(), None).collect();
+                        assert_eq!(removed, items);
+                    }
+                }
+            }
+        }
+
+        struct Log(Vec<Batch<SizedItem>>);
+        impl BatchTarget<SizedItem> for Log {
+            fn on_batch(&mut self, batch: Batch<SizedItem>) { self.0.push(batch); }
+        }
+
+        let mut mirror = vec![];
+        let mut batched = Batched::new(Log(vec![]));
+        let mut list = SkipList::new_from_iter_n(&mut batched, (0..50u8).map(|i| SizedItem(1 + i % 7)));
+        let mut rng = SmallRng::seed_from_u64(21);
+        for _ in 0..300 {
+            let len = list.len_user();
+            if len > 0 && rng.gen_bool(0.3) {
+                let (mut edit, offset) = list.edit_n(&mut batched, rng.gen_range(0, len));
+                edit.seek_to(edit.user_position() - offset);
+                edit.del(1);
+                edit.commit();
+            } else {
+                let items = (0..rng.gen_range(0, 4)).map(|_| SizedItem(rng.gen_range(1, 5))).collect::<Vec<_>>();
+                list.notify(&mut batched).insert_at(rng.gen_range(0, len + 1), items.into_iter());
+            }
+            for batch in batched.target_mut().0.drain(..) {
+                apply(&mut mirror, batch);
+            }
+            assert_eq!(&mirror, &Vec::from(&list));
+        }
+        list.check();
+    }
+
+    #[test]
+    fn annotations_follow_edits() {
+        let mut ann = Annotations::new();
+        let mut list = SkipList::new_from_iter_n(&mut ann, 0..100u32);
+
+        // A naive model: the range of each annotation, or None once deleted.
+        let mut model: Vec<(AnnotationId, Option<(usize, usize)>)> = vec![];
+        let mut rng = SmallRng::seed_from_u64(11);
+        for i in 0..500 {
+            let len = list.len_items();
+            match rng.gen_range(0, 5) {
+                0 if len > 0 => {
+                    let start = rng.gen_range(0, len);
+                    let end = rng.gen_range(start + 1, len + 1);
+                    let id = ann.add(start..end, i);
+                    model.push((id, Some((start, end))));
+                }
+                1 if !model.is_empty() => {
+                    let idx = rng.gen_range(0, model.len());
+                    let (id, r) = model[idx];
+                    assert_eq!(ann.remove(id).is_some(), r.is_some());
+                    model[idx].1 = None;
+                }
+                2 if len > 0 => {
+                    let pos = rng.gen_range(0, len);
+                    let n = rng.gen_range(1, (len - pos).min(20) + 1);
+                    list.notify(&mut ann).del_at(pos, n);
+                    let f = |x: usize| if x <= pos { x } else if x <= pos + n { pos } else { x - n };
+                    for (_, r) in model.iter_mut() {
+                        *r = r.map(|(s, e)| (f(s), f(e))).filter(|(s, e)| s < e);
+                    }
+                }
+                3 if len > 0 => {
+                    // Replaced items keep their annotations. Any extra items
+                    // are inserted after them, and any left over are deleted.
+                    let pos = rng.gen_range(0, len);
+                    let removed = rng.gen_range(1, (len - pos).min(20) + 1);
+                    let inserted = rng.gen_range(0, 20);
+                    list.notify(&mut ann).replace_at_slice(pos, removed, &vec![1; inserted]);
+                    if inserted >= removed {
+                        let (pos, n) = (pos + removed, inserted - removed);
+                        for (_, r) in model.iter_mut() {
+                            if let Some((s, e)) = r {
+                                if pos <= *s { *s += n; *e += n; } else if pos < *e { *e += n; }
+                            }
+                        }
+                    } else {
+                        let (pos, n) = (pos + inserted, removed - inserted);
+                        let f = |x: usize| if x <= pos { x } else if x <= pos + n { pos } else { x - n };
+                        for (_, r) in model.iter_mut() {
+                            *r = r.map(|(s, e)| (f(s), f(e))).filter(|(s, e)| s < e);
+                        }
+                    }
+                }
+                _ => {
+                    let pos = rng.gen_range(0, len + 1);
+                    let n = rng.gen_range(1, 20);
+                    list.notify(&mut ann).insert_at_slice(pos, &vec![0; n]);
+                    for (_, r) in model.iter_mut() {
+                        if let Some((s, e)) = r {
+                            if pos <= *s { *s += n; *e += n; } else if pos < *e { *e += n; }
+                        }
+                    }
+                }
+            }
+
+            let live: Vec<_> = model.iter().filter_map(|(id, r)| r.map(|r| (*id, r))).collect();
+            assert_eq!(ann.len(), live.len());
+            for (id, (s, e)) in &live {
+                assert_eq!(ann.range(*id), Some(*s..*e));
+            }
+
+            let len = list.len_items();
+            if len > 0 {
+                let a = rng.gen_range(0, len);
+                let b = rng.gen_range(a + 1, len + 1);
+                let mut found: Vec<AnnotationId> = ann.overlapping(a..b).iter().map(|(id, _)| *id).collect();
+                found.sort();
+                let expected: Vec<AnnotationId> = live.iter().filter(|(_, (s, e))| *s < b && *e > a).map(|(id, _)| *id).collect();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn annotations_inside_split_items() {
+        let mut ann = Annotations::new();
+        let mut list = SkipList::new_from_iter_n(&mut ann, into_iter::<SizedItem, _>(&[10]));
+        let id = ann.add(2..5, "comment");
+
+        // Splitting the item to insert text before the annotation moves it.
+        list.notify(&mut ann).insert_at(1, into_iter(&[3]));
+        assert_eq!(ann.range(id), Some(5..8));
+        list.notify(&mut ann).insert_at(6, into_iter(&[2]));
+        assert_eq!(ann.range(id), Some(5..10));
+        assert_eq!(ann.overlapping(0..6), vec![(id, &"comment")]);
+        assert!(ann.overlapping(10..15).is_empty());
+    }
+
+    #[test]
+    fn annotations_survive_replace() {
+        let mut ann = Annotations::new();
+        let mut list = SkipList::new_from_iter_n(&mut ann, 0..20u32);
+        let a = ann.add(2..8, "a");
+        let b = ann.add(4..5, "b");
+        let c = ann.add(6..11, "c");
+
+        list.notify(&mut ann).replace_at_slice(2, 1, &[100]);
+        assert_eq!(ann.range(a), Some(2..8));
+        list.notify(&mut ann).replace_at_slice(4, 1, &[100]);
+        assert_eq!(ann.range(b), Some(4..5));
+        // Replacing items from two nodes at once is the same as one at a time.
+        list.notify(&mut ann).replace_at_slice(9, 2, &[7, 7]);
+        assert_eq!(ann.range(c), Some(6..11));
+        assert_eq!(ann.len(), 3);
+    }
+
+    #[test]
+    fn annotations_replace_sized_items() {
+        let mut ann = Annotations::new();
+        let mut list = SkipList::new_from_iter_n(&mut ann, into_iter::<SizedItem, _>(&[2, 3, 2]));
+        let a = ann.add(2..5, "a");
+        let b = ann.add(0..7, "b");
+
+        // A bigger replacement extends the end of the item's range.
+        list.notify(&mut ann).replace_at(2, 1, into_iter(&[4]));
+        assert_eq!(ann.range(a), Some(2..6));
+        assert_eq!(ann.range(b), Some(0..8));
+        // A smaller one trims it.
+        list.notify(&mut ann).replace_at(2, 1, into_iter(&[1]));
+        assert_eq!(ann.range(a), Some(2..3));
+        assert_eq!(ann.range(b), Some(0..5));
+    }
+
+    #[test]
+    fn multi_edit() {
+        let mut list = SkipList::new_from_iter(0..30u8);
+        let mut edit = list.multi_edit(vec![20, 5, 10, 5]);
+        assert_eq!(edit.carets(), &[5, 10, 20]);
+
+        edit.insert_slice(&[100, 101]);
+        assert_eq!(edit.carets(), &[7, 14, 26]);
+        edit.del_backward(1);
+        assert_eq!(edit.carets(), &[6, 12, 23]);
+        // Deletes stop at the next caret, and carets which meet merge.
+        edit.del(8);
+        assert_eq!(edit.carets(), &[6, 9]);
+        let carets = edit.commit();
+        assert_eq!(carets, vec![6, 9]);
+
+        let mut expected: Vec<u8> = (0..30).collect();
+        expected.splice(20..20, [100]);
+        expected.splice(10..10, [100]);
+        expected.splice(5..5, [100]);
+        expected.drain(6..12);
+        expected.drain(6..14);
+        expected.drain(9..17);
+        check(&list, &expected);
+    }
+
+    // A model document for MultiEdit, with the carets stored between the
+    // items rather than as positions.
+    #[derive(Debug, Clone, Copy)]
+    enum Tok<T> { Item(T), Caret }
+
+    fn model_new<T: ListItem + Copy>(items: &[T], carets: &[usize]) -> Vec<Tok<T>> {
+        let mut carets = carets.to_vec();
+        carets.sort_unstable();
+        let mut carets = carets.into_iter().peekable();
+        let mut doc = vec![];
+        let mut pos = 0;
+        for &item in items {
+            let mut item = item;
+            loop {
+                match carets.peek() {
+                    Some(&c) if c == pos => {
+                        if !matches!(doc.last(), Some(Tok::Caret)) { doc.push(Tok::Caret); }
+                        carets.next();
+                    }
+                    Some(&c) if c < pos + item.get_usersize() => {
+                        let (a, b) = item.split_item(c - pos);
+                        doc.push(Tok::Item(a));
+                        pos = c;
+                        item = b;
+                    }
+                    _ => break,
+                }
+            }
+            pos += item.get_usersize();
+            doc.push(Tok::Item(item));
+        }
+        if carets.next().is_some() { doc.push(Tok::Caret); }
+        doc
+    }
+
+    fn model_items<T: Copy>(doc: &[Tok<T>]) -> Vec<T> {
+        doc.iter().filter_map(|t| match t { Tok::Item(i) => Some(*i), Tok::Caret => None }).collect()
+    }
+
+    fn model_carets<T: ListItem>(doc: &[Tok<T>]) -> Vec<usize> {
+        let mut pos = 0;
+        let mut carets = vec![];
+        for t in doc {
+            match t {
+                Tok::Item(i) => pos += i.get_usersize(),
+                Tok::Caret => carets.push(pos),
+            }
+        }
+        carets
+    }
+
+    fn model_insert<T: Copy>(doc: &mut Vec<Tok<T>>, items: &[T]) {
+        *doc = doc.iter().flat_map(|t| match t {
+            Tok::Caret => items.iter().map(|i| Tok::Item(*i)).chain(iter::once(Tok::Caret)).collect(),
+            t => vec![*t],
+        }).collect();
+    }
+
+    // Delete up to n items after each caret, without crossing another caret.
+    // Deleting backwards is the same thing on the reversed document.
+    fn model_del<T: Copy>(doc: &mut Vec<Tok<T>>, n: usize, backwards: bool) {
+        if backwards { doc.reverse(); }
+        let mut out = vec![];
+        let mut budget = 0;
+        for t in doc.drain(..) {
+            match t {
+                Tok::Caret => {
+                    budget = n;
+                    // Carets which meet merge.
+                    if !matches!(out.last(), Some(Tok::Caret)) { out.push(t); }
+                }
+                Tok::Item(_) if budget > 0 => budget -= 1,
+                Tok::Item(_) => {
+                    budget = 0;
+                    out.push(t);
+                }
+            }
+        }
+        *doc = out;
+        if backwards { doc.reverse(); }
+    }
+
+    fn multi_edit_random<T, F>(mut list: SkipList<T>, mut gen_item: F, seed: u64)
+    where T: ListItem + Copy + PartialEq + Debug, F: FnMut(&mut SmallRng) -> T {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        for _ in 0..50 {
+            let len = list.len_user();
+            let carets: Vec<usize> = (0..rng.gen_range(1, 8)).map(|_| rng.gen_range(0, len + 1)).collect();
+            let mut doc = model_new(&Vec::from(&list), &carets);
+            let mut edit = list.multi_edit(carets);
+            assert_eq!(edit.carets(), &model_carets(&doc)[..]);
+
+            for step in 0..5 {
+                let n = rng.gen_range(1, 5);
+                // Carets may start inside items, which only an insert allows.
+                match if step == 0 { 0 } else { rng.gen_range(0, 3) } {
+                    0 => {
+                        let items: Vec<T> = (0..n).map(|_| gen_item(&mut rng)).collect();
+                        edit.insert_slice(&items);
+                        model_insert(&mut doc, &items);
+                    }
+                    1 => {
+                        edit.del(n);
+                        model_del(&mut doc, n, false);
+                    }
+                    _ => {
+                        edit.del_backward(n);
+                        model_del(&mut doc, n, true);
+                    }
+                }
+                assert_eq!(edit.carets(), &model_carets(&doc)[..]);
+            }
+            edit.commit();
+            list.check();
+            assert_eq!(Vec::from(&list), model_items(&doc));
+        }
+    }
+
+    #[test]
+    fn multi_edit_matches_model() {
+        multi_edit_random(SkipList::new_from_iter(0..200u8), |rng| rng.gen::<u8>(), 5);
+    }
+
+    #[test]
+    fn multi_edit_sized_matches_model() {
+        // Carets land inside items, so inserts split them.
+        let list = SkipList::new_from_iter((0..100u8).map(|i| SizedItem(1 + i % 6)));
+        multi_edit_random(list, |rng| SizedItem(rng.gen_range(1, 4)), 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot delete from the middle of an item")]
+    fn multi_edit_del_inside_item() {
+        let mut list = SkipList::new_from_iter(into_iter::<SizedItem, u8>(&[3, 4, 5]));
+        let mut edit = list.multi_edit(vec![3, 5]);
+        edit.del(1);
+    }
+
+    struct U32Codec;
+    impl ItemCodec<u32> for U32Codec {
+        fn encode_item<W: std::io::Write>(&self, item: &u32, w: &mut W) -> std::io::Result<()> {
+            w.write_all(&item.to_le_bytes())
+        }
+        fn decode_item<R: std::io::Read>(&self, r: &mut R) -> std::io::Result<u32> {
+            let mut buf = [0u8; 4];
+            r.read_exact(&mut buf)?;
+            Ok(u32::from_le_bytes(buf))
+        }
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let list = SkipList::<u32>::new_from_iter(0..1000);
+        let mut bytes = vec![];
+        list.encode(&mut bytes, &U32Codec).unwrap();
+
+        let decoded = SkipList::<u32>::decode(&bytes[..], &U32Codec).unwrap();
+        decoded.check();
+        assert!(decoded.eq_list(&(0..1000).collect::<Vec<u32>>()));
+
+        // Truncated and corrupted input is an error, not a panic.
+        assert!(SkipList::<u32>::decode(&bytes[..bytes.len() - 1], &U32Codec).is_err());
+        let mut bad = bytes.clone();
+        bad[4] = 99;
+        assert!(matches!(SkipList::<u32>::decode(&bad[..], &U32Codec), Err(DecodeError::UnsupportedVersion(99))));
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(matches!(SkipList::<u32>::decode(&bad[..], &U32Codec), Err(DecodeError::BadMagic)));
+        // Header: magic, version, 2 byte varint item count, then the usercount.
+        let mut bad = bytes.clone();
+        bad[7] ^= 1;
+        assert!(matches!(SkipList::<u32>::decode(&bad[..], &U32Codec), Err(DecodeError::UsercountMismatch { .. })));
+        // Then the node count, and the head's size.
+        let mut bad = bytes;
+        bad[10] += 1;
+        assert!(matches!(SkipList::<u32>::decode(&bad[..], &U32Codec), Err(DecodeError::BadLayout)));
+    }
+
+    #[test]
+    fn binary_keeps_node_layout() {
+        let mut list = SkipList::<u32>::new_from_iter(0..1000);
+        let mut rng = SmallRng::seed_from_u64(5);
+        for _ in 0..200 {
+            let pos = rng.gen_range(0, list.len_items());
+            if rng.gen_bool(0.5) {
+                list.del_at(pos, rng.gen_range(0, 20).min(list.len_items() - pos));
+            } else {
+                list.insert_at_slice(pos, &[7; 13]);
+            }
+        }
+        let mut bytes = vec![];
+        list.encode(&mut bytes, &U32Codec).unwrap();
+
+        let decoded = SkipList::<u32>::decode(&bytes[..], &U32Codec).unwrap();
+        decoded.check();
+        assert!(decoded.eq_list(&Vec::from(&list)));
+        let mut again = vec![];
+        decoded.encode(&mut again, &U32Codec).unwrap();
+        assert_eq!(bytes, again);
+
+        // A freshly loaded list has a different layout.
+        let mut fresh = vec![];
+        decoded.iter().copied().collect::<SkipList<u32>>().encode(&mut fresh, &U32Codec).unwrap();
+        assert_ne!(bytes, fresh);
+
+        // Version 1 had no layout, and is bulk loaded.
+        let mut v1 = b"SKPL\x01\x03\x03".to_vec();
+        for i in 0..3u32 { U32Codec.encode_item(&i, &mut v1).unwrap(); }
+        let decoded = SkipList::<u32>::decode(&v1[..], &U32Codec).unwrap();
+        assert!(decoded.eq_list(&[0, 1, 2]));
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Wide(usize);
+    impl ListItem for Wide {
+        fn get_usersize(&self) -> usize { self.0 }
+    }
+    struct WideCodec;
+    impl ItemCodec<Wide> for WideCodec {
+        fn encode_item<W: std::io::Write>(&self, item: &Wide, w: &mut W) -> std::io::Result<()> {
+            w.write_all(&(item.0 as u64).to_le_bytes())
+        }
+        fn decode_item<R: std::io::Read>(&self, r: &mut R) -> std::io::Result<Wide> {
+            let mut buf = [0u8; 8];
+            r.read_exact(&mut buf)?;
+            Ok(Wide(u64::from_le_bytes(buf) as usize))
+        }
+    }
+
+    #[test]
+    fn binary_usercount_overflow() {
+        // Two items, one node, with sizes which add up to more than a usize.
+        let mut bytes = b"SKPL\x02\x02\x00\x01\x02".to_vec();
+        WideCodec.encode_item(&Wide(usize::MAX), &mut bytes).unwrap();
+        WideCodec.encode_item(&Wide(1), &mut bytes).unwrap();
+        assert!(matches!(SkipList::<Wide>::decode(&bytes[..], &WideCodec), Err(DecodeError::UsercountOverflow)));
+    }
 
     // Trashy non-performant implementation of the API for randomized testing.
-    fn vec_find_userpos<Item: ListItem>(list: &Vec<Item>, target_userpos: usize) -> usize {
+    fn vec_find_userpos<Item: ListItem>(list: &[Item], target_userpos: usize) -> usize {
         let mut item_pos = 0;
         let mut userpos = 0;
         while userpos != target_userpos {
@@ -317,15 +1865,14 @@ mod test {
         item_pos
     }
 
-    fn vec_insert_at<Item: ListItem>(list: &mut Vec<Item>, target_userpos: usize, content: &[Item]) where Item: Copy {
-        let mut item_pos = vec_find_userpos::<Item>(list, target_userpos);
+    fn vec_insert_at<Item: ListItem + Copy>(list: &mut Vec<Item>, target_userpos: usize, content: &[Item]) {
+        let item_pos = vec_find_userpos::<Item>(list, target_userpos);
         
-        for item in content {
+        for (i, item) in content.iter().enumerate() {
             // This is O(n^2) because of the moves, but this is testing code and
             // its fine. The old code was more complex to make this fast, but I
             // thats probably overkill here.
-            list.insert(item_pos, *item);
-            item_pos += 1;
+            list.insert(item_pos + i, *item);
         }
     }
 
@@ -335,7 +1882,7 @@ mod test {
         list.drain(item_pos .. item_pos+num_items);
     }
 
-    fn vec_replace<Item: ListItem>(list: &mut Vec<Item>, target_userpos: usize, removed_items: usize, inserted_content: &[Item]) where Item: Copy {
+    fn vec_replace<Item: ListItem + Copy>(list: &mut Vec<Item>, target_userpos: usize, removed_items: usize, inserted_content: &[Item]) {
         vec_delete_at::<Item>(list, target_userpos, removed_items);
         vec_insert_at::<Item>(list, target_userpos, inserted_content);
     }
@@ -353,7 +1900,7 @@ mod test {
         content
     }
 
-    fn random_edits<Item: ListItem>(gen_item: fn(r: &mut SmallRng) -> Item) where Item: PartialEq + Debug + Copy {
+    fn random_edits<Item>(gen_item: fn(r: &mut SmallRng) -> Item) where Item: ListItem + PartialEq + Debug + Copy {
         let mut list = SkipList::<Item>::new();
         let mut vec = Vec::<Item>::new();
 
@@ -425,7 +1972,7 @@ mod test {
 
 
     // use std::marker::PhantomData;
-    struct DropItem<'a>(TDItem<'a>);
+    struct DropItem<'a>(#[allow(dead_code)] TDItem<'a>);
     impl<'a> ListItem for DropItem<'a> {}
 
     #[test]
@@ -454,6 +2001,41 @@ mod test {
         td.assert_drop(id2);
     }
 
+    #[test]
+    fn compacted_contents_dropped() {
+        let td = TestDrop::new();
+        let mut list = SkipList::<DropItem>::new();
+        let mut ids = Vec::new();
+        for _ in 0..100 {
+            let (id, item) = td.new_item();
+            ids.push(id);
+            list.insert_at(0, iter::once(DropItem(item)));
+        }
+
+        list.compact();
+        assert_eq!(td.num_dropped_items(), 0);
+
+        drop(list);
+        for id in ids { td.assert_drop(id); }
+    }
+
+    #[test]
+    fn pooled_contents_dropped() {
+        let td = TestDrop::new();
+        let mut list = SkipList::<DropItem>::new();
+        list.enable_node_pool();
+        let mut ids = Vec::new();
+        for _ in 0..1000 {
+            let (id, item) = td.new_item();
+            ids.push(id);
+            list.insert_at(0, iter::once(DropItem(item)));
+        }
+
+        list.del_at(0, 1000);
+        assert!(list.num_pooled_nodes() > 0);
+        for id in ids { td.assert_drop(id); }
+    }
+
     #[test]
     fn deleted_contents_dropped() {
         let td = TestDrop::new();